@@ -1,10 +1,16 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use api::{
     env::{load_public, load_secret},
     flatgeobuf::FgbSource,
-    regions::{regions, route, Regions},
-    routing::StadiaMapsRouting,
+    regions::{greener_trip, regions, route, routes, trip, trips, Regions},
+    routing::{
+        fixture::FixtureRouting, graphhopper::GraphHopperRouting, osrm::OsrmRouting,
+        valhalla::ValhallaRouting, RoutingProvider,
+    },
     state::AppState,
     tracing::{init_opentelemetry_from_environment, init_safe_default_from_environment},
 };
@@ -13,7 +19,7 @@ use axum::{
     routing::get,
     Router,
 };
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use tower_http::{
     compression::CompressionLayer,
     cors::{Any, CorsLayer},
@@ -35,6 +41,56 @@ struct Args {
     /// enable opentelemetry
     #[arg(long)]
     opentelemetry: bool,
+
+    /// where routes come from, with its endpoint and any key from the environment
+    #[arg(long, env = "ROUTING", value_enum, default_value_t = Routing::StadiaMaps)]
+    routing: Routing,
+
+    /// GeoJSON line strings to replay as routes, for `--routing fixture`
+    #[arg(long, env = "ROUTING_FIXTURE", required_if_eq("routing", "fixture"))]
+    routing_fixture: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Routing {
+    /// Stadia Maps' hosted Valhalla, using `STADIA_MAPS_API_KEY` and
+    /// `STADIA_MAPS_ENDPOINT_BASE`
+    StadiaMaps,
+    /// a self-hosted Valhalla at `VALHALLA_ENDPOINT_BASE`
+    Valhalla,
+    /// a self-hosted OSRM at `OSRM_ENDPOINT_BASE`
+    Osrm,
+    /// GraphHopper at `GRAPHHOPPER_ENDPOINT_BASE`, with `GRAPHHOPPER_API_KEY` if set
+    #[value(name = "graphhopper")]
+    GraphHopper,
+    /// the same recorded routes for every trip, from `--routing-fixture`
+    Fixture,
+}
+
+fn routing_provider(
+    routing: Routing,
+    fixture: Option<&Path>,
+) -> Result<Arc<dyn RoutingProvider>, Box<dyn std::error::Error>> {
+    let endpoint_base = |name: &str| -> Result<Url, Box<dyn std::error::Error>> {
+        Ok(Url::parse(&load_public(name)?)?)
+    };
+    Ok(match routing {
+        Routing::StadiaMaps => Arc::new(ValhallaRouting::stadia_maps(
+            &load_secret("STADIA_MAPS_API_KEY")?,
+            &endpoint_base("STADIA_MAPS_ENDPOINT_BASE")?,
+        )?),
+        Routing::Valhalla => Arc::new(ValhallaRouting::new(&endpoint_base(
+            "VALHALLA_ENDPOINT_BASE",
+        )?)?),
+        Routing::Osrm => Arc::new(OsrmRouting::new(&endpoint_base("OSRM_ENDPOINT_BASE")?)),
+        Routing::GraphHopper => Arc::new(GraphHopperRouting::new(
+            &endpoint_base("GRAPHHOPPER_ENDPOINT_BASE")?,
+            load_secret("GRAPHHOPPER_API_KEY").ok().as_deref(),
+        )?),
+        Routing::Fixture => Arc::new(FixtureRouting::from_path(
+            fixture.ok_or("No routing fixture specified")?,
+        )?),
+    })
 }
 
 #[tracing::instrument()]
@@ -63,8 +119,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         init_safe_default_from_environment()?;
     }
 
-    let stadia_maps_api_key = load_secret("STADIA_MAPS_API_KEY")?;
-    let stadia_maps_endpoint_base = Url::parse(&load_public("STADIA_MAPS_ENDPOINT_BASE")?)?;
+    let routing = routing_provider(args.routing, args.routing_fixture.as_deref())?;
+    info!("Using routing: {:?}", args.routing);
 
     let cors = CorsLayer::new()
         .allow_methods([Method::GET])
@@ -79,21 +135,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     info!("Using FlatGeobuf source: {}", flatgeobuf);
+    let dissolved = flatgeobuf.is_dissolved().await?;
+    info!("FlatGeobuf regions already dissolved: {}", dissolved);
 
     let app = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
         .route("/v2/regions", get(regions))
         .route("/v2/route", get(route))
+        .route("/v2/trip", get(trip))
+        .route("/v2/routes", get(routes))
+        .route("/v2/trips", get(trips))
+        .route("/v2/trip/greener", get(greener_trip))
         .route("/health", get(health))
         .layer(cors)
         .layer(CompressionLayer::new())
         .with_state(AppState {
             flatgeobuf,
-            regions: Arc::new(Regions::default()),
-            routing: Arc::new(StadiaMapsRouting::new(
-                &stadia_maps_api_key,
-                &stadia_maps_endpoint_base,
-            )?),
+            regions: Arc::new(Regions::new(dissolved)),
+            routing,
         });
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
@@ -4,15 +4,18 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use core_geo::Bounds;
+use core_geo::{Bounds, DEGRADED_PROPERTY, DISSOLVED_LAYER};
 use flatgeobuf::{
-    geozero::ToGeo, AsyncFeatureIter, FallibleStreamingIterator, FgbReader, HttpFgbReader,
+    geozero::ToGeo, AsyncFeatureIter, FallibleStreamingIterator, FeatureProperties, FgbReader,
+    HttpFgbReader,
 };
 use geo::Geometry;
 use std::fmt::Display;
 use tracing::{instrument, trace};
 use url::Url;
 
+use crate::regions::Areas;
+
 pub enum FgbSource {
     File(FgbFileSource),
     Url(FgbUrlSource),
@@ -28,16 +31,25 @@ impl Display for FgbSource {
 }
 
 impl FgbSource {
-    pub async fn load(
-        &self,
-        bounds: &Bounds,
-    ) -> Result<Vec<Geometry<f64>>, Box<dyn std::error::Error>> {
+    /// The features within `bounds`, degraded where the builder set their
+    /// `DEGRADED_PROPERTY`, which only dissolved areas have
+    pub async fn load(&self, bounds: &Bounds) -> Result<Areas, Box<dyn std::error::Error>> {
         match self {
             FgbSource::File(source) => source.load(bounds),
             FgbSource::Url(source) => source.load(bounds).await,
         }
     }
 
+    /// Whether the builder dissolved the regions, going by the layer name it gave
+    /// them, so they don't need unioned again
+    pub async fn is_dissolved(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        let layer_name = match self {
+            FgbSource::File(source) => source.layer_name()?,
+            FgbSource::Url(source) => source.layer_name().await?,
+        };
+        Ok(layer_name == DISSOLVED_LAYER)
+    }
+
     pub fn from_path(path: &Path) -> Self {
         FgbSource::File(FgbFileSource {
             path: path.to_path_buf(),
@@ -54,8 +66,13 @@ pub struct FgbFileSource {
 }
 
 impl FgbFileSource {
+    fn layer_name(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let reader = FgbReader::open(BufReader::new(File::open(&self.path)?))?;
+        Ok(reader.header().name().unwrap_or_default().to_string())
+    }
+
     #[instrument(skip(self))]
-    fn load(&self, bounds: &Bounds) -> Result<Vec<Geometry<f64>>, Box<dyn std::error::Error>> {
+    fn load(&self, bounds: &Bounds) -> Result<Areas, Box<dyn std::error::Error>> {
         let filein = BufReader::new(File::open(self.path.clone())?);
         trace!("Opening reader for FlatGeobuf file: {:?}", self.path);
         let reader = FgbReader::open(filein)?;
@@ -67,17 +84,18 @@ impl FgbFileSource {
         trace!("Selected bbox");
 
         trace!("Iterating over features");
-        let mut geoms: Vec<Geometry<f64>> = vec![];
+        let mut areas = Areas::default();
         while let Some(feature) = features.next()? {
             let geom: Geometry<f64> = feature.to_geo()?;
-            geoms.push(geom);
+            areas.geometry.push(geom);
+            areas.degraded.push(is_degraded(feature));
         }
         trace!(
             "Finished iterating over features, found {} geoms",
-            geoms.len()
+            areas.geometry.len()
         );
 
-        Ok(geoms)
+        Ok(areas)
     }
 }
 
@@ -86,19 +104,21 @@ pub struct FgbUrlSource {
 }
 
 impl FgbUrlSource {
+    async fn layer_name(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let reader = HttpFgbReader::open(self.url.as_ref()).await?;
+        Ok(reader.header().name().unwrap_or_default().to_string())
+    }
+
     #[instrument(skip(self))]
-    async fn load(
-        &self,
-        bounds: &Bounds,
-    ) -> Result<Vec<Geometry<f64>>, Box<dyn std::error::Error>> {
+    async fn load(&self, bounds: &Bounds) -> Result<Areas, Box<dyn std::error::Error>> {
         trace!("Opening reader for FlatGeobuf URL: {:?}", self.url);
-        let reader = HttpFgbReader::open(&self.url.to_string()).await?;
+        let reader = HttpFgbReader::open(self.url.as_ref()).await?;
         trace!("Opened reader");
 
-        let mut features = select_bbox(reader, &bounds).await?;
-        let geoms = load_geoms(&mut features).await?;
+        let mut features = select_bbox(reader, bounds).await?;
+        let areas = load_geoms(&mut features).await?;
 
-        Ok(geoms)
+        Ok(areas)
     }
 }
 
@@ -113,23 +133,103 @@ async fn select_bbox(
 }
 
 #[instrument(skip(features))]
-async fn load_geoms(
-    features: &mut AsyncFeatureIter,
-) -> Result<Vec<Geometry>, Box<dyn std::error::Error>> {
+async fn load_geoms(features: &mut AsyncFeatureIter) -> Result<Areas, Box<dyn std::error::Error>> {
     trace!("Iterating over features");
-    let mut geoms: Vec<Geometry<f64>> = vec![];
+    let mut areas = Areas::default();
     let mut report_at = 1;
     while let Some(feature) = features.next().await? {
         let geom: Geometry<f64> = feature.to_geo()?;
-        geoms.push(geom);
-        if geoms.len() == report_at {
-            trace!("Loaded {} geoms", geoms.len());
+        areas.geometry.push(geom);
+        areas.degraded.push(is_degraded(feature));
+        if areas.geometry.len() == report_at {
+            trace!("Loaded {} geoms", areas.geometry.len());
             report_at *= 2;
         }
     }
     trace!(
         "Finished iterating over features, found {} geoms",
-        geoms.len()
+        areas.geometry.len()
     );
-    Ok(geoms)
+    Ok(areas)
+}
+
+/// Whether `feature` has its `DEGRADED_PROPERTY` set; those without the column
+/// aren't degraded
+fn is_degraded(feature: &impl FeatureProperties) -> bool {
+    feature.property::<bool>(DEGRADED_PROPERTY).unwrap_or(false)
+}
+
+/// `polygons` written to a FlatGeobuf in a directory which lasts as long as the
+/// `TempDir`
+#[cfg(test)]
+pub(crate) fn fgb_of(polygons: Vec<geo::Polygon<f64>>) -> (tempfile::TempDir, FgbSource) {
+    let mut fgb = flatgeobuf::FgbWriter::create("all", flatgeobuf::GeometryType::Polygon).unwrap();
+    for polygon in polygons {
+        fgb.add_feature_geom(Geometry::Polygon(polygon), |_| {})
+            .unwrap();
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("regions.fgb");
+    fgb.write(&mut File::create(&path).unwrap()).unwrap();
+    (dir, FgbSource::from_path(&path))
+}
+
+#[cfg(test)]
+mod tests {
+    use flatgeobuf::geozero::{ColumnValue, PropertyProcessor};
+    use flatgeobuf::{ColumnType, FgbWriter, GeometryType};
+    use geo::{coord, BoundingRect, Rect};
+
+    use super::*;
+
+    async fn written_as(layer_name: &str) -> bool {
+        let mut fgb = FgbWriter::create(layer_name, GeometryType::Polygon).unwrap();
+        let square = Rect::new(coord! { x: 0., y: 0. }, coord! { x: 1., y: 1. });
+        fgb.add_feature_geom(Geometry::Polygon(square.to_polygon()), |_| {})
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("regions.fgb");
+        fgb.write(&mut File::create(&path).unwrap()).unwrap();
+        FgbSource::from_path(&path).is_dissolved().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn dissolved_is_read_from_the_layer_name() {
+        assert!(written_as(DISSOLVED_LAYER).await);
+        assert!(!written_as("all").await);
+    }
+
+    #[tokio::test]
+    async fn degraded_is_read_from_the_property() {
+        let mut fgb = FgbWriter::create(DISSOLVED_LAYER, GeometryType::Polygon).unwrap();
+        fgb.add_column(DEGRADED_PROPERTY, ColumnType::Bool, |_, _| {});
+        for (x, degraded) in [(0., false), (2., true)] {
+            let square = Rect::new(coord! { x: x, y: 0. }, coord! { x: x + 1., y: 1. });
+            fgb.add_feature_geom(Geometry::Polygon(square.to_polygon()), |feature| {
+                feature
+                    .property(0, DEGRADED_PROPERTY, &ColumnValue::Bool(degraded))
+                    .unwrap();
+            })
+            .unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("regions.fgb");
+        fgb.write(&mut File::create(&path).unwrap()).unwrap();
+
+        let bounds = Bounds {
+            sw_lat: 0.,
+            sw_lon: 0.,
+            ne_lat: 1.,
+            ne_lon: 3.,
+        };
+        let areas = FgbSource::from_path(&path).load(&bounds).await.unwrap();
+        let mut degraded: Vec<(f64, bool)> = areas
+            .geometry
+            .iter()
+            .zip(areas.degraded)
+            .map(|(g, degraded)| (g.bounding_rect().unwrap().min().x, degraded))
+            .collect();
+        degraded.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert_eq!(degraded, vec![(0., false), (2., true)]);
+    }
 }
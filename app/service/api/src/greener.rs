@@ -0,0 +1,347 @@
+use std::time::Duration;
+
+use geo::{
+    Contains, GeodesicLength, HaversineDistance, LineInterpolatePoint, LineString, Point, Polygon,
+    Rect,
+};
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, instrument};
+
+use crate::regions::{expand_by_metres, LabelledRoute};
+use crate::score::GreenScore;
+use crate::state::AppState;
+use crate::trip::Trip;
+
+/// Stretches of a route shorter than this, in metres, aren't worth routing around
+const MIN_STRETCH_METRES: f64 = 100.0;
+
+/// How far, in metres, an avoided area reaches from the middle of a stretch
+const AVOID_METRES: f64 = 30.0;
+
+/// How many stretches are avoided each time the route is perturbed
+const STRETCHES_PER_STEP: usize = 2;
+
+/// The most times a route is perturbed, however long the time budget
+const MAX_STEPS: usize = 10;
+
+/// How much longer than the first route a greener route can be
+const MAX_DETOUR: f64 = 1.5;
+
+/// Why a greener route couldn't be found
+#[derive(Debug)]
+pub enum GreenerError {
+    /// the routing provider couldn't route the trip
+    Routing(Box<dyn std::error::Error>),
+    /// the route couldn't be labelled against the regions
+    Labelling(Box<dyn std::error::Error>),
+    /// the budget ran out before the first route was found and labelled
+    OutOfTime,
+}
+
+impl std::fmt::Display for GreenerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GreenerError::Routing(e) => write!(f, "routing failed: {}", e),
+            GreenerError::Labelling(e) => write!(f, "labelling failed: {}", e),
+            GreenerError::OutOfTime => write!(f, "out of time before the first route was found"),
+        }
+    }
+}
+
+impl std::error::Error for GreenerError {}
+
+/// The greenest route found, and the scores of every route tried, in order
+pub struct Perturbed {
+    pub best: LabelledRoute,
+    pub history: Vec<GreenScore>,
+}
+
+/// Repeatedly re-route the trip around the longest stretches of it which aren't
+/// through greenery, keeping the greenest route found within `budget`. Finding and
+/// labelling the first route counts against the budget too, and it's an error if
+/// that can't be done in time.
+#[instrument(skip(state))]
+pub async fn greener_route(
+    state: &AppState,
+    trip: &Trip,
+    near_metres: f64,
+    budget: Duration,
+) -> Result<Perturbed, GreenerError> {
+    let deadline = Instant::now() + budget;
+    let first = timeout_at(deadline, labelled_route(state, trip, &[], near_metres))
+        .await
+        .map_err(|_| GreenerError::OutOfTime)??;
+    let max_length = first.score.length_metres * MAX_DETOUR;
+    let mut history = vec![first.score];
+    let mut best = first.clone();
+    let mut current = first;
+    let mut avoid: Vec<Polygon<f64>> = vec![];
+
+    for step in 0..MAX_STEPS {
+        let outside: Vec<LineString<f64>> = current
+            .not_green
+            .iter()
+            .chain(current.near.iter())
+            .cloned()
+            .collect();
+        let more = avoidances(&outside, trip, &avoid);
+        if more.is_empty() {
+            debug!("nothing more to avoid after {} steps", step);
+            break;
+        }
+        avoid.extend(more);
+
+        let perturbed =
+            timeout_at(deadline, labelled_route(state, trip, &avoid, near_metres)).await;
+        current = match perturbed {
+            Ok(Ok(labelled)) => labelled,
+            Ok(Err(e)) => {
+                debug!("stopping after {} steps, as re-routing failed: {}", step, e);
+                break;
+            }
+            Err(_) => {
+                debug!("out of time after {} steps", step);
+                break;
+            }
+        };
+        history.push(current.score);
+        if current.score.green_percent > best.score.green_percent
+            && current.score.length_metres <= max_length
+        {
+            best = current.clone();
+        }
+    }
+
+    Ok(Perturbed { best, history })
+}
+
+/// A route for the trip avoiding the `avoid` areas, labelled
+async fn labelled_route(
+    state: &AppState,
+    trip: &Trip,
+    avoid: &[Polygon<f64>],
+    near_metres: f64,
+) -> Result<LabelledRoute, GreenerError> {
+    let route = state
+        .routing
+        .find_trip_route_avoiding(trip, avoid)
+        .await
+        .map_err(GreenerError::Routing)?;
+    state
+        .regions
+        .label_route(&state.flatgeobuf, &route, near_metres)
+        .await
+        .map_err(GreenerError::Labelling)
+}
+
+/// Areas around the middles of the longest `outside` stretches, leaving out
+/// stretches which are short, already avoided, or too close to the trip's places
+/// to be avoided without making the trip impossible
+fn avoidances(
+    outside: &[LineString<f64>],
+    trip: &Trip,
+    avoided: &[Polygon<f64>],
+) -> Vec<Polygon<f64>> {
+    let places: Vec<Point<f64>> = std::iter::once(trip.origin)
+        .chain(trip.via.iter().copied())
+        .chain(std::iter::once(trip.destination))
+        .map(Point::from)
+        .collect();
+
+    let mut stretches: Vec<(f64, Point<f64>)> = outside
+        .iter()
+        .map(|line| (line.geodesic_length(), line))
+        .filter(|(length, _)| *length >= MIN_STRETCH_METRES)
+        .filter_map(|(length, line)| Some((length, line.line_interpolate_point(0.5)?)))
+        .filter(|(_, middle)| !avoided.iter().any(|area| area.contains(middle)))
+        .filter(|(_, middle)| {
+            places
+                .iter()
+                .all(|place| place.haversine_distance(middle) > 2.0 * AVOID_METRES)
+        })
+        .collect();
+    stretches.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    stretches
+        .into_iter()
+        .take(STRETCHES_PER_STEP)
+        .map(|(_, middle)| {
+            expand_by_metres(Rect::new(middle.0, middle.0), AVOID_METRES).to_polygon()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use geo::{coord, line_string};
+
+    use super::*;
+    use crate::flatgeobuf::fgb_of;
+    use crate::regions::Regions;
+    use crate::routing::{fixture::FixtureRouting, RouteOptions, RoutingProvider};
+
+    /// About 1.1km east along the equator
+    fn trip() -> Trip {
+        Trip {
+            origin: coord! { x: 0., y: 0. },
+            destination: coord! { x: 0.01, y: 0. },
+            via: vec![],
+        }
+    }
+
+    /// A park a little north of the trip
+    fn park() -> Polygon<f64> {
+        Rect::new(
+            coord! { x: 0.0025, y: 0.002 },
+            coord! { x: 0.0075, y: 0.004 },
+        )
+        .to_polygon()
+    }
+
+    /// Straight along the trip, through no greenery
+    fn straight() -> LineString<f64> {
+        line_string![(x: 0., y: 0.), (x: 0.01, y: 0.)]
+    }
+
+    /// Across the park, a quarter longer than `straight`
+    fn through_park() -> LineString<f64> {
+        line_string![(x: 0., y: 0.), (x: 0.003, y: 0.003), (x: 0.007, y: 0.003), (x: 0.01, y: 0.)]
+    }
+
+    /// Back and forth across the park, twice as long as `straight`
+    fn around_park() -> LineString<f64> {
+        line_string![
+            (x: 0., y: 0.), (x: 0.003, y: 0.003), (x: 0.007, y: 0.003), (x: 0.007, y: 0.0035),
+            (x: 0.003, y: 0.0035), (x: 0.003, y: 0.0025), (x: 0.007, y: 0.0025), (x: 0.01, y: 0.),
+        ]
+    }
+
+    /// Replays recorded routes, each after a delay
+    struct SlowRouting {
+        fixture: FixtureRouting,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl RoutingProvider for SlowRouting {
+        async fn routes(
+            &self,
+            trip: &Trip,
+            options: &RouteOptions,
+        ) -> Result<Vec<LineString>, Box<dyn std::error::Error>> {
+            tokio::time::sleep(self.delay).await;
+            self.fixture.routes(trip, options).await
+        }
+    }
+
+    async fn greener(
+        routing: Arc<dyn RoutingProvider>,
+        budget: Duration,
+    ) -> Result<Perturbed, GreenerError> {
+        let (_dir, fgb) = fgb_of(vec![park()]);
+        let state = AppState {
+            flatgeobuf: Arc::new(fgb),
+            regions: Arc::new(Regions::default()),
+            routing,
+        };
+        greener_route(&state, &trip(), 0.0, budget).await
+    }
+
+    #[tokio::test]
+    async fn greenest_route_is_kept() {
+        let routing = Arc::new(FixtureRouting::new(vec![straight(), through_park()]));
+        let perturbed = greener(routing, Duration::from_secs(5)).await.unwrap();
+
+        assert_eq!(perturbed.best.route, through_park());
+        assert_eq!(perturbed.history.len(), 2);
+        assert_eq!(perturbed.history[0].green_percent, 0.0);
+        assert!(perturbed.history[1].green_percent > 30.0);
+    }
+
+    #[tokio::test]
+    async fn greener_routes_with_too_long_a_detour_are_not_kept() {
+        let routing = Arc::new(FixtureRouting::new(vec![straight(), around_park()]));
+        let perturbed = greener(routing, Duration::from_secs(5)).await.unwrap();
+
+        assert_eq!(perturbed.best.route, straight());
+        assert_eq!(perturbed.history.len(), 2);
+        assert!(
+            perturbed.history[1].length_metres > perturbed.history[0].length_metres * MAX_DETOUR
+        );
+        assert!(perturbed.history[1].green_percent > 50.0);
+    }
+
+    #[tokio::test]
+    async fn rerouting_stops_at_the_deadline() {
+        let routing = || {
+            Arc::new(SlowRouting {
+                fixture: FixtureRouting::new(vec![straight(), through_park()]),
+                delay: Duration::from_millis(200),
+            })
+        };
+        // time for the first route, but not the second
+        let perturbed = greener(routing(), Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert_eq!(perturbed.best.route, straight());
+        assert_eq!(perturbed.history.len(), 1);
+
+        // not even time for the first
+        assert!(matches!(
+            greener(routing(), Duration::from_millis(100)).await,
+            Err(GreenerError::OutOfTime)
+        ));
+    }
+
+    #[tokio::test]
+    async fn routing_and_labelling_failures_are_told_apart() {
+        let unroutable = greener(
+            Arc::new(FixtureRouting::new(vec![])),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(matches!(unroutable, Err(GreenerError::Routing(_))));
+
+        // the regions file is gone by the time the route is labelled
+        let (dir, fgb) = fgb_of(vec![park()]);
+        drop(dir);
+        let state = AppState {
+            flatgeobuf: Arc::new(fgb),
+            regions: Arc::new(Regions::default()),
+            routing: Arc::new(FixtureRouting::new(vec![straight()])),
+        };
+        let unlabellable = greener_route(&state, &trip(), 0.0, Duration::from_secs(5)).await;
+        assert!(matches!(unlabellable, Err(GreenerError::Labelling(_))));
+    }
+
+    #[test]
+    fn longest_stretches_away_from_places_are_avoided() {
+        // a hundredth of a degree along the equator is about 1.1km
+        let trip = Trip {
+            origin: coord! { x: 0., y: 0. },
+            destination: coord! { x: 0.1, y: 0. },
+            via: vec![coord! { x: 0.05, y: 0. }],
+        };
+        let outside = vec![
+            // around the via point
+            line_string![(x: 0.04, y: 0.), (x: 0.06, y: 0.)],
+            line_string![(x: 0.01, y: 0.), (x: 0.02, y: 0.)],
+            line_string![(x: 0.02, y: 0.001), (x: 0.04, y: 0.001)],
+            line_string![(x: 0.07, y: 0.), (x: 0.1, y: 0.)],
+            // too short to bother with
+            line_string![(x: 0.065, y: 0.), (x: 0.0655, y: 0.)],
+        ];
+
+        let avoid = avoidances(&outside, &trip, &[]);
+        assert_eq!(avoid.len(), 2);
+        assert!(avoid[0].contains(&Point::new(0.085, 0.)));
+        assert!(avoid[1].contains(&Point::new(0.03, 0.001)));
+
+        let more = avoidances(&outside, &trip, &avoid);
+        assert_eq!(more.len(), 1);
+        assert!(more[0].contains(&Point::new(0.015, 0.)));
+    }
+}
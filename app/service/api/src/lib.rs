@@ -1,6 +1,9 @@
 pub mod flatgeobuf;
+pub mod greener;
 pub mod routing;
 pub mod env;
 pub mod regions;
+pub mod score;
 pub mod state;
 pub mod tracing;
+pub mod trip;
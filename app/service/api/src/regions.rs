@@ -1,39 +1,126 @@
 use axum::extract::State;
+use axum::http::StatusCode;
 use axum::{extract::Query, Json};
-use core_geo::union::union;
-use core_geo::Bounds;
+use core_geo::buffer::buffer_multi_polygon_metres;
+use core_geo::union::{flatten, union};
+use core_geo::{Bounds, DEGRADED_PROPERTY};
 use geo::geometry::{Geometry, GeometryCollection};
-use geo::{BooleanOps, BoundingRect, LineString, MultiLineString, MultiPolygon, Polygon};
+use geo::{
+    coord, BooleanOps, BoundingRect, Intersects, LineString, MultiLineString, MultiPolygon, Rect,
+};
 use geojson::feature::Id;
 use geojson::FeatureCollection;
 use geojson::GeoJson;
-use rstar::RTree;
+use rstar::{RTree, RTreeObject};
+use serde::Deserialize;
 use std::iter::FromIterator;
+use std::time::Duration;
 use tracing::instrument;
 
 use crate::flatgeobuf::FgbSource;
+use crate::greener::{greener_route, GreenerError};
+use crate::score::GreenScore;
 use crate::state::AppState;
+use crate::trip::{Trip, TripQuery};
 
 #[derive(Default)]
-pub struct Regions;
+pub struct Regions {
+    /// whether the source was built with `--dissolve`, so has no overlapping
+    /// areas which need unioned
+    dissolved: bool,
+}
+
+/// The furthest from greenery, in metres, that a route can be labelled as near it
+const MAX_NEAR_METRES: f64 = 200.0;
+
+/// Roughly how many metres there are in a degree of latitude
+const METRES_PER_DEGREE: f64 = 111_320.0;
+
+/// The parts of a route going through greenery, near it, and neither
+#[derive(Clone)]
+pub struct LabelledRoute {
+    pub(crate) route: LineString<f64>,
+    pub(crate) green: MultiLineString<f64>,
+    pub(crate) near: MultiLineString<f64>,
+    pub(crate) not_green: MultiLineString<f64>,
+    pub(crate) score: GreenScore,
+    /// the green areas needed a fallback to union, so may not be exact
+    pub(crate) degraded: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct RouteLayers {
+    /// how close, in metres, a part of the route must be to greenery to be labelled
+    /// as near it; up to `MAX_NEAR_METRES`, and nothing is near if not given
+    pub near_metres: Option<f64>,
+}
 
-struct LabelledRoute {
-    route: LineString<f64>,
-    green: MultiLineString<f64>,
+/// Green areas, as loaded or unioned
+#[derive(Default, Debug)]
+pub struct Areas {
+    pub geometry: Vec<Geometry<f64>>,
+    /// for each of `geometry`, whether a fallback was needed to union it, so it may
+    /// not be exact
+    pub degraded: Vec<bool>,
+}
+
+/// The most alternative routes which can be asked for
+const MAX_ALTERNATIVES: usize = 3;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Alternatives {
+    /// how many routes to find besides the best one, up to `MAX_ALTERNATIVES`;
+    /// one if not given
+    pub alternatives: Option<usize>,
+}
+
+impl Alternatives {
+    fn count(&self) -> usize {
+        self.alternatives.unwrap_or(1).min(MAX_ALTERNATIVES)
+    }
+}
+
+impl RouteLayers {
+    /// The requested distance, limited to `0..=MAX_NEAR_METRES`
+    fn near_metres(&self) -> f64 {
+        self.near_metres
+            .filter(|metres| metres.is_finite())
+            .map_or(0.0, |metres| metres.clamp(0.0, MAX_NEAR_METRES))
+    }
 }
 
 impl Regions {
+    pub fn new(dissolved: bool) -> Self {
+        Regions { dissolved }
+    }
+
+    /// Union `areas`, unless they were already dissolved by the builder; either way,
+    /// an area is degraded if any of those it was made from were
+    fn union(&self, areas: Areas) -> Result<Areas, Box<dyn std::error::Error>> {
+        if self.dissolved {
+            return Ok(areas);
+        }
+        let unioned = union(areas.geometry)?;
+        let degraded = (0..unioned.geometry.len())
+            .map(|i| {
+                unioned.is_degraded(i) || unioned.sources[i].iter().any(|s| areas.degraded[*s])
+            })
+            .collect();
+        Ok(Areas {
+            geometry: unioned.geometry,
+            degraded,
+        })
+    }
+
     #[instrument(skip(self, fgb, bounds))]
     pub async fn regions(
         &self,
         fgb: &FgbSource,
         bounds: Bounds,
-    ) -> Result<GeometryCollection<f64>, Box<dyn std::error::Error>> {
-        let geoms = fgb.load(&bounds).await?;
-
-        let unioned: Vec<Geometry<f64>> = union(geoms)?;
+    ) -> Result<Areas, Box<dyn std::error::Error>> {
+        let areas = fgb.load(&bounds).await?;
 
-        Ok(GeometryCollection::from_iter(unioned))
+        self.union(areas)
     }
 
     #[instrument(skip(self, fgb, route))]
@@ -41,90 +128,350 @@ impl Regions {
         &self,
         fgb: &FgbSource,
         route: &LineString<f64>,
+        near_metres: f64,
     ) -> Result<LabelledRoute, Box<dyn std::error::Error>> {
-        let route_bounding_rect = route.bounding_rect().expect("some bounding rect");
+        let mut labelled = self
+            .label_routes(fgb, std::slice::from_ref(route), near_metres)
+            .await?;
+        Ok(labelled.remove(0))
+    }
 
-        let regions = fgb.load(&route_bounding_rect.into()).await?;
+    /// Each of the routes labelled, ranked by the share of them through greenery.
+    /// The green areas near them all are loaded and unioned together once.
+    #[instrument(skip(self, fgb, routes))]
+    pub async fn label_routes(
+        &self,
+        fgb: &FgbSource,
+        routes: &[LineString<f64>],
+        near_metres: f64,
+    ) -> Result<Vec<LabelledRoute>, Box<dyn std::error::Error>> {
+        // regions up to `near_metres` outside a route's bounds can still be near it
+        let route_rects = routes
+            .iter()
+            .map(|route| {
+                let rect = route.bounding_rect().ok_or("route has no coords")?;
+                Ok(expand_by_metres(rect, near_metres))
+            })
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+        let Some(bounds) = route_rects.iter().copied().reduce(|a, b| {
+            Rect::new(
+                coord! { x: a.min().x.min(b.min().x), y: a.min().y.min(b.min().y) },
+                coord! { x: a.max().x.max(b.max().x), y: a.max().y.max(b.max().y) },
+            )
+        }) else {
+            return Ok(vec![]);
+        };
 
-        let possible = Regions::find_possibly_overlapping_regions(
-            &regions,
-            &route_bounding_rect.to_polygon(),
-        )?;
-        let overlaps = possible.clip(&MultiLineString::new(vec![route.clone()]), false);
+        let regions = fgb.load(&bounds.into()).await?;
 
-        Ok(LabelledRoute {
-            route: route.clone(),
-            green: overlaps,
-        })
+        let nearby = Nearby::new(
+            self.find_possibly_overlapping_regions(regions, &route_rects)?,
+            near_metres,
+        );
+        let mut labelled_routes: Vec<LabelledRoute> = routes
+            .iter()
+            .zip(&route_rects)
+            .map(|(route, rect)| nearby.label(route, rect))
+            .collect();
+        labelled_routes.sort_by(|a, b| b.score.green_percent.total_cmp(&a.score.green_percent));
+        Ok(labelled_routes)
     }
 
-    #[instrument(skip(regions, route))]
+    /// The union of the regions which may overlap any of `route_rects`
+    #[instrument(skip(self, regions, route_rects))]
     fn find_possibly_overlapping_regions(
-        regions: &[Geometry],
-        route: &Polygon,
-    ) -> Result<MultiPolygon, Box<dyn std::error::Error>> {
-        let polygons = regions
-            .iter()
-            .filter_map(|g| match g {
-                Geometry::Polygon(p) => Some(p.clone()),
-                _ => None,
-            })
-            .collect::<Vec<_>>();
-
-        let route_rtree = RTree::bulk_load(vec![route.clone()]);
-        let region_rtree = RTree::bulk_load(polygons);
-        let mut overlap_candidates = vec![];
-        for (poly, _) in region_rtree.intersection_candidates_with_other_tree(&route_rtree) {
-            overlap_candidates.push(Geometry::Polygon(poly.clone()))
+        &self,
+        regions: Areas,
+        route_rects: &[Rect<f64>],
+    ) -> Result<Areas, Box<dyn std::error::Error>> {
+        let route_rtree = RTree::bulk_load(route_rects.iter().map(|r| r.to_polygon()).collect());
+        let (polygons, inputs) = flatten(regions.geometry);
+        let mut overlap_candidates = Areas::default();
+        for (polygon, input) in polygons.into_iter().zip(inputs) {
+            let envelope = polygon.envelope();
+            if route_rtree
+                .locate_in_envelope_intersecting(&envelope)
+                .next()
+                .is_some()
+            {
+                overlap_candidates.geometry.push(Geometry::Polygon(polygon));
+                overlap_candidates.degraded.push(regions.degraded[input]);
+            }
         }
-        let unioned = union(overlap_candidates)?;
+        self.union(overlap_candidates)
+    }
+}
 
-        let union_polygons = unioned
-            .iter()
-            .filter_map(|g| match g {
-                Geometry::Polygon(p) => Some(p.clone()),
-                _ => None,
-            })
-            .collect::<Vec<_>>();
+/// The unioned green areas near some routes, for labelling each of them
+struct Nearby {
+    areas: Areas,
+    polygons: MultiPolygon<f64>,
+    /// everywhere within `near_metres` of `polygons`, if anything can be near them
+    surroundings: Option<MultiPolygon<f64>>,
+}
 
-        Ok(MultiPolygon::new(union_polygons))
+impl Nearby {
+    fn new(areas: Areas, near_metres: f64) -> Self {
+        let polygons = MultiPolygon::new(
+            areas
+                .geometry
+                .iter()
+                .filter_map(|g| match g {
+                    Geometry::Polygon(p) => Some(p.clone()),
+                    _ => None,
+                })
+                .collect(),
+        );
+        let surroundings =
+            (near_metres > 0.0).then(|| buffer_multi_polygon_metres(&polygons, near_metres));
+        Nearby {
+            areas,
+            polygons,
+            surroundings,
+        }
+    }
+
+    /// Label `route`, whose bounds grown by `near_metres` are `rect`
+    fn label(&self, route: &LineString<f64>, rect: &Rect<f64>) -> LabelledRoute {
+        let route_lines = MultiLineString::new(vec![route.clone()]);
+        let green = self.polygons.clip(&route_lines, false);
+        let outside = self.polygons.clip(&route_lines, true);
+        let score = GreenScore::new(route, &green, &outside);
+        let (near, not_green) = match &self.surroundings {
+            Some(surroundings) => (
+                surroundings.clip(&outside, false),
+                surroundings.clip(&outside, true),
+            ),
+            None => (MultiLineString::new(vec![]), outside),
+        };
+        // only the areas near this route count against it
+        let degraded =
+            self.areas
+                .geometry
+                .iter()
+                .zip(&self.areas.degraded)
+                .any(|(geometry, degraded)| {
+                    *degraded
+                        && geometry
+                            .bounding_rect()
+                            .is_some_and(|bounds| bounds.intersects(rect))
+                });
+        LabelledRoute {
+            route: route.clone(),
+            green,
+            near,
+            not_green,
+            score,
+            degraded,
+        }
     }
 }
 
 #[instrument(skip(state))]
-pub async fn regions(state: State<AppState>, Query(bounds): Query<Bounds>) -> Json<GeoJson> {
+pub async fn regions(
+    state: State<AppState>,
+    Query(bounds): Query<Bounds>,
+) -> Result<Json<GeoJson>, (StatusCode, String)> {
     let regions = state.regions.clone();
     let fgb = state.flatgeobuf.clone();
-    let geometry_collection = regions.regions(&fgb, bounds).await.unwrap();
-    Json(as_geojson(&geometry_collection))
+    let Areas { geometry, degraded } = regions
+        .regions(&fgb, bounds)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut geojson = as_geojson(&GeometryCollection::from_iter(geometry));
+    if let GeoJson::FeatureCollection(feature_collection) = &mut geojson {
+        for (feature, degraded) in feature_collection.features.iter_mut().zip(degraded) {
+            if degraded {
+                feature.set_property(DEGRADED_PROPERTY, true);
+            }
+        }
+    }
+    Ok(Json(geojson))
+}
+
+/// `rect` grown by about `metres` on every side
+pub(crate) fn expand_by_metres(rect: Rect<f64>, metres: f64) -> Rect<f64> {
+    let lat = rect.center().y.to_radians();
+    let margin = coord! {
+        x: metres / (METRES_PER_DEGREE * lat.cos().max(0.01)),
+        y: metres / METRES_PER_DEGREE,
+    };
+    Rect::new(rect.min() - margin, rect.max() + margin)
 }
 
 #[instrument(skip(state))]
 pub async fn route(
     state: State<AppState>,
     Query(bounds): Query<Bounds>,
-) -> Json<serde_json::Value> {
+    Query(layers): Query<RouteLayers>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let regions = state.regions.clone();
     let fgb = state.flatgeobuf.clone();
     let routing = state.routing.clone();
-    let route = routing.find_route(&bounds).await.unwrap();
-    let labelled_route = regions.label_route(&fgb, &route).await.unwrap();
+    let route = routing
+        .find_route(&bounds)
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    let labelled_route = regions
+        .label_route(&fgb, &route, layers.near_metres())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(labelled_route_json(labelled_route)))
+}
+
+/// Like `route`, but between the given origin and destination, through any via points
+#[instrument(skip(state))]
+pub async fn trip(
+    state: State<AppState>,
+    Query(query): Query<TripQuery>,
+    Query(layers): Query<RouteLayers>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let trip = Trip::try_from(&query).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let regions = state.regions.clone();
+    let fgb = state.flatgeobuf.clone();
+    let routing = state.routing.clone();
+    let route = routing
+        .find_trip_route(&trip)
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    let labelled_route = regions
+        .label_route(&fgb, &route, layers.near_metres())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(labelled_route_json(labelled_route)))
+}
+
+/// Like `route`, but with alternative routes too, greenest first
+#[instrument(skip(state))]
+pub async fn routes(
+    state: State<AppState>,
+    Query(bounds): Query<Bounds>,
+    Query(layers): Query<RouteLayers>,
+    Query(alternatives): Query<Alternatives>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let routing = state.routing.clone();
+    let routes = routing
+        .find_routes(&bounds, alternatives.count())
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    let labelled_routes = state
+        .regions
+        .label_routes(&state.flatgeobuf, &routes, layers.near_metres())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(labelled_routes_json(labelled_routes)))
+}
+
+/// Like `trip`, but with alternative routes too, greenest first
+#[instrument(skip(state))]
+pub async fn trips(
+    state: State<AppState>,
+    Query(query): Query<TripQuery>,
+    Query(layers): Query<RouteLayers>,
+    Query(alternatives): Query<Alternatives>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let trip = Trip::try_from(&query).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let routing = state.routing.clone();
+    let routes = routing
+        .find_trip_routes(&trip, alternatives.count())
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    let labelled_routes = state
+        .regions
+        .label_routes(&state.flatgeobuf, &routes, layers.near_metres())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(labelled_routes_json(labelled_routes)))
+}
+
+/// The most time, in milliseconds, which can be spent looking for a greener route
+const MAX_BUDGET_MILLIS: u64 = 5_000;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Budget {
+    /// how long to spend looking for a greener route, up to `MAX_BUDGET_MILLIS`;
+    /// a second if not given
+    pub budget_millis: Option<u64>,
+}
+
+impl Budget {
+    fn duration(&self) -> Duration {
+        Duration::from_millis(self.budget_millis.unwrap_or(1_000).min(MAX_BUDGET_MILLIS))
+    }
+}
+
+/// Like `trip`, but re-routed around parts which aren't green for as long as the
+/// budget allows, with the scores of every route tried
+#[instrument(skip(state))]
+pub async fn greener_trip(
+    state: State<AppState>,
+    Query(query): Query<TripQuery>,
+    Query(layers): Query<RouteLayers>,
+    Query(budget): Query<Budget>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let trip = Trip::try_from(&query).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let perturbed = greener_route(&state, &trip, layers.near_metres(), budget.duration())
+        .await
+        .map_err(|e| {
+            let status = match e {
+                GreenerError::Routing(_) => StatusCode::BAD_GATEWAY,
+                GreenerError::OutOfTime => StatusCode::GATEWAY_TIMEOUT,
+                GreenerError::Labelling(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, e.to_string())
+        })?;
+    let mut json = labelled_route_json(perturbed.best);
+    if let Some(parts) = json.as_object_mut() {
+        parts.insert(
+            "history".to_string(),
+            serde_json::to_value(perturbed.history).unwrap(),
+        );
+    }
+    Ok(Json(json))
+}
+
+fn labelled_routes_json(labelled_routes: Vec<LabelledRoute>) -> serde_json::Value {
+    let routes = labelled_routes
+        .into_iter()
+        .map(labelled_route_json)
+        .collect();
+    serde_json::Value::Object(serde_json::Map::from_iter(vec![(
+        "routes".to_string(),
+        serde_json::Value::Array(routes),
+    )]))
+}
+
+fn labelled_route_json(labelled_route: LabelledRoute) -> serde_json::Value {
     let route_geojson = as_geojson(&GeometryCollection::from(vec![Geometry::LineString(
         labelled_route.route,
     )]));
-    let green_json = as_geojson(&GeometryCollection::from(vec![Geometry::MultiLineString(
-        labelled_route.green,
-    )]));
+    let layer_json = |lines: MultiLineString<f64>| {
+        let geojson = as_geojson(&GeometryCollection::from(vec![Geometry::MultiLineString(
+            lines,
+        )]));
+        serde_json::to_value(geojson).unwrap()
+    };
 
     let route_json = serde_json::to_value(route_geojson).unwrap();
-    let green_json = serde_json::to_value(green_json).unwrap();
 
     let parts: serde_json::Map<String, serde_json::Value> = serde_json::Map::from_iter(vec![
         ("route".to_string(), route_json),
-        ("green".to_string(), green_json),
+        ("green".to_string(), layer_json(labelled_route.green)),
+        ("near".to_string(), layer_json(labelled_route.near)),
+        (
+            "not_green".to_string(),
+            layer_json(labelled_route.not_green),
+        ),
+        (
+            "score".to_string(),
+            serde_json::to_value(labelled_route.score).unwrap(),
+        ),
+        (
+            "degraded".to_string(),
+            serde_json::Value::Bool(labelled_route.degraded),
+        ),
     ]);
-    let json = serde_json::Value::Object(parts);
-    Json(json)
+    serde_json::Value::Object(parts)
 }
 
 fn as_geojson(geometry_collection: &GeometryCollection<f64>) -> GeoJson {
@@ -134,3 +481,104 @@ fn as_geojson(geometry_collection: &GeometryCollection<f64>) -> GeoJson {
     }
     GeoJson::FeatureCollection(feature_collection)
 }
+
+#[cfg(test)]
+mod tests {
+    use geo::{HaversineLength, MultiLineString, Polygon};
+
+    use super::*;
+    use crate::flatgeobuf::fgb_of;
+
+    /// A park about 550m across and 110m tall, in Edinburgh
+    fn park() -> Polygon<f64> {
+        Rect::new(
+            coord! { x: -3.20, y: 55.950 },
+            coord! { x: -3.19, y: 55.951 },
+        )
+        .to_polygon()
+    }
+
+    /// A route running about 300m east along the south side of `park`, `metres`
+    /// south of it
+    fn route_south_of_park(metres: f64) -> LineString<f64> {
+        let lat = 55.950 - metres / METRES_PER_DEGREE;
+        LineString::from(vec![(-3.198, lat), (-3.193, lat)])
+    }
+
+    fn length(lines: &MultiLineString<f64>) -> f64 {
+        lines.haversine_length()
+    }
+
+    #[tokio::test]
+    async fn routes_are_near_greenery_within_near_metres() {
+        let (_dir, fgb) = fgb_of(vec![park()]);
+        let route = route_south_of_park(30.0);
+        let route_length = route.haversine_length();
+
+        let near = Regions::default()
+            .label_route(&fgb, &route, 50.0)
+            .await
+            .unwrap();
+        assert!(length(&near.green) < 1e-6);
+        assert!((length(&near.near) - route_length).abs() < 1.0);
+        assert!(length(&near.not_green) < 1.0);
+
+        let far = Regions::default()
+            .label_route(&fgb, &route, 10.0)
+            .await
+            .unwrap();
+        assert!(length(&far.near) < 1e-6);
+        assert!((length(&far.not_green) - route_length).abs() < 1.0);
+    }
+
+    #[tokio::test]
+    async fn routes_through_greenery_are_green() {
+        let (_dir, fgb) = fgb_of(vec![park()]);
+        // into the park from 30m south of it
+        let route = LineString::from(vec![
+            (-3.195, 55.950 - 30.0 / METRES_PER_DEGREE),
+            (-3.195, 55.9505),
+        ]);
+
+        let labelled = Regions::default()
+            .label_route(&fgb, &route, 50.0)
+            .await
+            .unwrap();
+        assert!((length(&labelled.green) - 0.0005 * METRES_PER_DEGREE).abs() < 1.0);
+        assert!((length(&labelled.near) - 30.0).abs() < 1.0);
+        assert!(length(&labelled.not_green) < 1e-6);
+        assert!(!labelled.degraded);
+    }
+
+    #[tokio::test]
+    async fn routes_are_ranked_by_green_percent() {
+        let (_dir, fgb) = fgb_of(vec![park()]);
+        let outside = route_south_of_park(30.0);
+        // half in the park
+        let half = LineString::from(vec![(-3.1925, 55.9505), (-3.1875, 55.9505)]);
+        // all in the park
+        let inside = LineString::from(vec![(-3.199, 55.9505), (-3.191, 55.9505)]);
+
+        let labelled = Regions::default()
+            .label_routes(&fgb, &[outside.clone(), half.clone(), inside.clone()], 50.0)
+            .await
+            .unwrap();
+        let ranked: Vec<&LineString<f64>> = labelled.iter().map(|l| &l.route).collect();
+        assert_eq!(ranked, vec![&inside, &half, &outside]);
+        let percents: Vec<f64> = labelled.iter().map(|l| l.score.green_percent).collect();
+        assert!((percents[0] - 100.0).abs() < 0.1, "{:?}", percents);
+        assert!((percents[1] - 50.0).abs() < 1.0, "{:?}", percents);
+        assert!(percents[2] < 0.1, "{:?}", percents);
+    }
+
+    #[test]
+    fn near_metres_are_clamped() {
+        let near = |near_metres| RouteLayers { near_metres }.near_metres();
+        assert_eq!(near(None), 0.0);
+        assert_eq!(near(Some(50.0)), 50.0);
+        assert_eq!(near(Some(-10.0)), 0.0);
+        assert_eq!(near(Some(1_000.0)), MAX_NEAR_METRES);
+        assert_eq!(near(Some(f64::NAN)), 0.0);
+        assert_eq!(near(Some(f64::INFINITY)), 0.0);
+    }
+}
@@ -1,97 +1,152 @@
-use std::time::SystemTime;
-
-use axum::http::HeaderMap;
+use async_trait::async_trait;
 use core_geo::Bounds;
-use ferrostar::{
-    models::{GeographicCoordinate, UserLocation, Waypoint, WaypointKind},
-    routing_adapters::{
-        osrm::OsrmResponseParser, valhalla::ValhallaHttpRequestGenerator, RouteRequest,
-        RouteRequestGenerator, RouteResponseParser,
-    },
-};
-use geo::{coord, LineString};
-use tracing::{instrument, trace};
-use url::Url;
-
-pub struct StadiaMapsRouting {
-    route_url: Url,
+use geo::{coord, LineString, Polygon};
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::trip::Trip;
+
+pub mod fixture;
+pub mod graphhopper;
+pub mod osrm;
+pub mod valhalla;
+
+/// What to ask of a routing backend besides the trip itself
+#[derive(Debug, Default, Clone)]
+pub struct RouteOptions {
+    /// how many alternatives to the best route to find, if the backend can
+    pub alternates: usize,
+    /// areas the routes mustn't go through
+    pub avoid: Vec<Polygon<f64>>,
 }
 
-impl StadiaMapsRouting {
-    pub fn new(api_key: &str, endpoint_base: &Url) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut route_url = endpoint_base.join("/route/v1")?;
-        let authenticated_route_url = route_url
-            .query_pairs_mut()
-            .append_pair("api_key", api_key)
-            .finish();
-        Ok(StadiaMapsRouting {
-            route_url: authenticated_route_url.clone(),
-        })
+/// Something which finds walking routes
+#[async_trait]
+pub trait RoutingProvider: Send + Sync {
+    /// The best route for the trip, followed by any alternatives to it. Backends
+    /// which can't avoid areas return an error when asked to, and all return an
+    /// error rather than no routes or routes without at least two coords.
+    async fn routes(
+        &self,
+        trip: &Trip,
+        options: &RouteOptions,
+    ) -> Result<Vec<LineString>, Box<dyn std::error::Error>>;
+
+    /// A route between two points invented from the viewport `bounds`
+    #[instrument(skip(self, bounds))]
+    async fn find_route(&self, bounds: &Bounds) -> Result<LineString, Box<dyn std::error::Error>> {
+        self.find_trip_route(&viewport_trip(bounds)).await
     }
 
+    /// Like `find_route`, but with up to `alternates` alternative routes as well
     #[instrument(skip(self, bounds))]
-    pub async fn find_route(
+    async fn find_routes(
         &self,
         bounds: &Bounds,
+        alternates: usize,
+    ) -> Result<Vec<LineString>, Box<dyn std::error::Error>> {
+        self.find_trip_routes(&viewport_trip(bounds), alternates)
+            .await
+    }
+
+    /// A route from the trip's origin to its destination, passing through its via points
+    #[instrument(skip(self))]
+    async fn find_trip_route(&self, trip: &Trip) -> Result<LineString, Box<dyn std::error::Error>> {
+        self.find_trip_route_avoiding(trip, &[]).await
+    }
+
+    /// The best route for the trip, followed by up to `alternates` alternatives to
+    /// it. Not every backend finds alternatives for trips with via points.
+    #[instrument(skip(self))]
+    async fn find_trip_routes(
+        &self,
+        trip: &Trip,
+        alternates: usize,
+    ) -> Result<Vec<LineString>, Box<dyn std::error::Error>> {
+        let options = RouteOptions {
+            alternates,
+            ..Default::default()
+        };
+        let mut routes = self.routes(trip, &options).await?;
+        routes.truncate(alternates + 1);
+        Ok(routes)
+    }
+
+    /// A route for the trip which doesn't go through any of the `avoid` areas
+    #[instrument(skip(self, avoid))]
+    async fn find_trip_route_avoiding(
+        &self,
+        trip: &Trip,
+        avoid: &[Polygon<f64>],
     ) -> Result<LineString, Box<dyn std::error::Error>> {
-        let bounds_width = (bounds.ne_lon - bounds.sw_lon).abs();
-        let bounds_height = (bounds.ne_lat - bounds.sw_lat).abs();
-        let corner1 = coord! {
-        x: bounds.sw_lon + (bounds_width / 5.0),
-        y: bounds.ne_lat - (bounds_height / 2.0) + (0.02 * bounds_height / 2.0) };
-        let corner2 = coord! {
-        x: bounds.ne_lon - (bounds_width / 5.0),
-        y: bounds.sw_lat + (bounds_height / 2.0) - (0.02 * bounds_height / 2.0)};
-
-        let generator = ValhallaHttpRequestGenerator::new(
-            self.route_url.to_string().clone(),
-            "pedestrian".into(),
-            None,
-        );
-
-        let user_location = UserLocation {
-            coordinates: GeographicCoordinate {
-                lat: corner1.y,
-                lng: corner1.x,
-            },
-            horizontal_accuracy: 1.0,
-            course_over_ground: None,
-            timestamp: SystemTime::now(),
-            speed: None,
+        let options = RouteOptions {
+            avoid: avoid.to_vec(),
+            ..Default::default()
         };
-        let waypoints: Vec<Waypoint> = vec![Waypoint {
-            coordinate: GeographicCoordinate {
-                lat: corner2.y,
-                lng: corner2.x,
-            },
-            kind: WaypointKind::Break,
-        }];
-
-        let RouteRequest::HttpPost { url, body, headers } =
-            generator.generate_request(user_location, waypoints)?;
-
-        let client = reqwest::Client::new();
-        let response = client
-            .post(url)
-            .body(body)
-            .headers(HeaderMap::try_from(&headers)?)
-            .send()
-            .await?;
-
-        let content = response.bytes().await?;
-        let routes = OsrmResponseParser::new(6).parse_response(content.to_vec())?;
-
-        trace!("Converting {:?} routes", routes.len());
-
-        let route = routes.first().unwrap();
-        let route_line = LineString::new(
-            route
-                .geometry
+        let routes = self.routes(trip, &options).await?;
+        Ok(routes.into_iter().next().ok_or("no route found")?)
+    }
+}
+
+/// Whether `route` is a line at all, which backends have been known to return
+/// routes that aren't
+fn is_usable(route: &LineString) -> bool {
+    route.0.len() >= 2
+}
+
+/// The usable routes a backend found, or an error if there are none, so that
+/// nothing downstream has to cope with an empty route
+fn found(routes: Vec<LineString>) -> Result<Vec<LineString>, Box<dyn std::error::Error>> {
+    let routes: Vec<LineString> = routes.into_iter().filter(is_usable).collect();
+    if routes.is_empty() {
+        return Err("no usable route found".into());
+    }
+    Ok(routes)
+}
+
+/// A trip between two points a fifth of the way in from the sides of `bounds`,
+/// near its vertical middle
+fn viewport_trip(bounds: &Bounds) -> Trip {
+    let bounds_width = (bounds.ne_lon - bounds.sw_lon).abs();
+    let bounds_height = (bounds.ne_lat - bounds.sw_lat).abs();
+    let corner1 = coord! {
+    x: bounds.sw_lon + (bounds_width / 5.0),
+    y: bounds.ne_lat - (bounds_height / 2.0) + (0.02 * bounds_height / 2.0) };
+    let corner2 = coord! {
+    x: bounds.ne_lon - (bounds_width / 5.0),
+    y: bounds.sw_lat + (bounds_height / 2.0) - (0.02 * bounds_height / 2.0)};
+
+    Trip {
+        origin: corner1,
+        destination: corner2,
+        via: vec![],
+    }
+}
+
+/// The trip's places in order, as `[lon, lat]`
+fn lon_lats(trip: &Trip) -> Vec<[f64; 2]> {
+    std::iter::once(trip.origin)
+        .chain(trip.via.iter().copied())
+        .chain(std::iter::once(trip.destination))
+        .map(|coord| [coord.x, coord.y])
+        .collect()
+}
+
+/// A GeoJSON line geometry, as OSRM and GraphHopper return routes; any elevation
+/// is ignored
+#[derive(Deserialize, Debug)]
+struct GeoJsonLine {
+    coordinates: Vec<Vec<f64>>,
+}
+
+impl From<GeoJsonLine> for LineString {
+    fn from(line: GeoJsonLine) -> Self {
+        LineString::new(
+            line.coordinates
                 .iter()
-                .map(|c| coord!(x: c.lng, y: c.lat))
+                .filter(|position| position.len() >= 2)
+                .map(|position| coord! { x: position[0], y: position[1] })
                 .collect(),
-        );
-
-        Ok(route_line)
+        )
     }
 }
@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use geo::{Geometry, Intersects, LineString};
+use geojson::GeoJson;
+use tracing::instrument;
+
+use super::{is_usable, RouteOptions, RoutingProvider};
+use crate::trip::Trip;
+
+/// Routing which replays the same recorded routes whatever the trip, so can be
+/// used locally and in tests without a routing service
+pub struct FixtureRouting {
+    routes: Vec<LineString>,
+}
+
+impl FixtureRouting {
+    /// Routes without at least two coords are left out
+    pub fn new(routes: Vec<LineString>) -> Self {
+        FixtureRouting {
+            routes: routes.into_iter().filter(is_usable).collect(),
+        }
+    }
+
+    /// The line strings in a GeoJSON file, in order
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let geojson: GeoJson = std::fs::read_to_string(path)?.parse()?;
+        let collection = geo::GeometryCollection::<f64>::try_from(&geojson)?;
+        let routes: Vec<LineString> = collection
+            .into_iter()
+            .filter_map(|geometry| match geometry {
+                Geometry::LineString(line) => Some(line),
+                _ => None,
+            })
+            .collect();
+        if routes.is_empty() {
+            return Err(format!("{} has no line strings to replay", path.display()).into());
+        }
+        Ok(FixtureRouting::new(routes))
+    }
+}
+
+#[async_trait]
+impl RoutingProvider for FixtureRouting {
+    /// The recorded routes which don't go through any avoided areas, in order
+    #[instrument(skip(self, options))]
+    async fn routes(
+        &self,
+        _trip: &Trip,
+        options: &RouteOptions,
+    ) -> Result<Vec<LineString>, Box<dyn std::error::Error>> {
+        let routes: Vec<LineString> = self
+            .routes
+            .iter()
+            .filter(|route| !options.avoid.iter().any(|area| area.intersects(*route)))
+            .take(options.alternates + 1)
+            .cloned()
+            .collect();
+        if routes.is_empty() {
+            return Err("no recorded route avoids the areas".into());
+        }
+        Ok(routes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use geo::{coord, line_string, Rect};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn recorded_routes_are_replayed_in_order() {
+        let routing: Arc<dyn RoutingProvider> = Arc::new(FixtureRouting::new(vec![
+            line_string![(x: 0., y: 0.), (x: 1., y: 0.)],
+            line_string![(x: 0., y: 0.), (x: 0.5, y: 1.), (x: 1., y: 0.)],
+            line_string![(x: 0., y: 0.), (x: 0.5, y: -1.), (x: 1., y: 0.)],
+        ]));
+        let trip = Trip {
+            origin: coord! { x: 0., y: 0. },
+            destination: coord! { x: 1., y: 0. },
+            via: vec![],
+        };
+
+        let routes = routing.find_trip_routes(&trip, 1).await.unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].0.len(), 2);
+
+        let flat = Rect::new(coord! { x: 0.4, y: -0.1 }, coord! { x: 0.6, y: 0.1 });
+        let above = Rect::new(coord! { x: 0.4, y: 0.9 }, coord! { x: 0.6, y: 1.1 });
+        let route = routing
+            .find_trip_route_avoiding(&trip, &[flat.to_polygon(), above.to_polygon()])
+            .await
+            .unwrap();
+        assert_eq!(route.0[1], coord! { x: 0.5, y: -1. });
+
+        let everywhere = Rect::new(coord! { x: -1., y: -2. }, coord! { x: 2., y: 2. });
+        assert!(routing
+            .find_trip_route_avoiding(&trip, &[everywhere.to_polygon()])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn routes_without_two_coords_are_left_out() {
+        let routing: Arc<dyn RoutingProvider> = Arc::new(FixtureRouting::new(vec![
+            LineString::new(vec![]),
+            line_string![(x: 0., y: 0.)],
+            line_string![(x: 0., y: 0.), (x: 1., y: 0.)],
+        ]));
+        let trip = Trip {
+            origin: coord! { x: 0., y: 0. },
+            destination: coord! { x: 1., y: 0. },
+            via: vec![],
+        };
+        let route = routing.find_trip_route(&trip).await.unwrap();
+        assert_eq!(route.0.len(), 2);
+    }
+}
@@ -0,0 +1,162 @@
+use async_trait::async_trait;
+use geo::{LineString, Polygon};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{instrument, trace};
+use url::Url;
+
+use super::{found, lon_lats, GeoJsonLine, RouteOptions, RoutingProvider};
+use crate::trip::Trip;
+
+/// Routing by GraphHopper, either self-hosted or its hosted API with a key
+pub struct GraphHopperRouting {
+    route_url: Url,
+    profile: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphHopperResponse {
+    message: Option<String>,
+    #[serde(default)]
+    paths: Vec<GraphHopperPath>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphHopperPath {
+    points: GeoJsonLine,
+}
+
+impl GraphHopperRouting {
+    pub fn new(
+        endpoint_base: &Url,
+        api_key: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut route_url = endpoint_base.join("/route")?;
+        if let Some(api_key) = api_key {
+            route_url.query_pairs_mut().append_pair("key", api_key);
+        }
+        Ok(GraphHopperRouting {
+            route_url,
+            profile: "foot".to_string(),
+        })
+    }
+
+    fn request(&self, trip: &Trip, options: &RouteOptions) -> serde_json::Value {
+        let mut request = json!({
+            "points": lon_lats(trip),
+            "profile": self.profile,
+            "points_encoded": false,
+            "instructions": false,
+        });
+        if options.alternates > 0 {
+            request["algorithm"] = "alternative_route".into();
+            request["alternative_route.max_paths"] = (options.alternates + 1).into();
+        }
+        if !options.avoid.is_empty() {
+            // areas can only be avoided with a custom model, which can't use the
+            // precomputed contraction hierarchies
+            request["ch.disable"] = true.into();
+            request["custom_model"] = custom_model(&options.avoid);
+        }
+        request
+    }
+}
+
+/// A GraphHopper custom model which won't go through any of the areas
+fn custom_model(avoid: &[Polygon<f64>]) -> serde_json::Value {
+    let id = |i: usize| format!("avoid_{}", i);
+    let priority: Vec<serde_json::Value> = (0..avoid.len())
+        .map(|i| json!({ "if": format!("in_{}", id(i)), "multiply_by": "0" }))
+        .collect();
+    let features: Vec<serde_json::Value> = avoid
+        .iter()
+        .enumerate()
+        .map(|(i, polygon)| {
+            json!({
+                "type": "Feature",
+                "id": id(i),
+                "properties": {},
+                "geometry": geojson::Geometry::from(polygon),
+            })
+        })
+        .collect();
+    json!({
+        "priority": priority,
+        "areas": { "type": "FeatureCollection", "features": features },
+    })
+}
+
+#[async_trait]
+impl RoutingProvider for GraphHopperRouting {
+    #[instrument(skip(self, options))]
+    async fn routes(
+        &self,
+        trip: &Trip,
+        options: &RouteOptions,
+    ) -> Result<Vec<LineString>, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
+        let response = client
+            .post(self.route_url.clone())
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&self.request(trip, options))?)
+            .send()
+            .await?;
+        let status = response.status();
+        let content = response.bytes().await?;
+        let response: GraphHopperResponse = serde_json::from_slice(&content)?;
+        if !status.is_success() {
+            return Err(format!(
+                "GraphHopper couldn't route, {}: {}",
+                status,
+                response.message.unwrap_or_default()
+            )
+            .into());
+        }
+
+        trace!("Converting {:?} routes", response.paths.len());
+
+        found(
+            response
+                .paths
+                .into_iter()
+                .map(|path| path.points.into())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{coord, Rect};
+
+    use super::*;
+
+    #[test]
+    fn avoided_areas_are_in_a_custom_model() {
+        let graphhopper =
+            GraphHopperRouting::new(&Url::parse("http://localhost:8989").unwrap(), None).unwrap();
+        let trip = Trip {
+            origin: coord! { x: -3.19, y: 55.95 },
+            destination: coord! { x: -3.16, y: 55.94 },
+            via: vec![],
+        };
+        let avoid = Rect::new(coord! { x: -3.18, y: 55.94 }, coord! { x: -3.17, y: 55.95 });
+        let request = graphhopper.request(
+            &trip,
+            &RouteOptions {
+                alternates: 0,
+                avoid: vec![avoid.to_polygon()],
+            },
+        );
+
+        assert_eq!(request["points"], json!([[-3.19, 55.95], [-3.16, 55.94]]));
+        assert_eq!(request["ch.disable"], json!(true));
+        assert_eq!(
+            request["custom_model"]["priority"],
+            json!([{ "if": "in_avoid_0", "multiply_by": "0" }])
+        );
+        let area = &request["custom_model"]["areas"]["features"][0];
+        assert_eq!(area["id"], json!("avoid_0"));
+        assert_eq!(area["geometry"]["type"], json!("Polygon"));
+    }
+}
@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use geo::LineString;
+use serde::Deserialize;
+use tracing::{instrument, trace};
+use url::Url;
+
+use super::{found, lon_lats, GeoJsonLine, RouteOptions, RoutingProvider};
+use crate::trip::Trip;
+
+/// Routing by a self-hosted OSRM, which can't avoid areas
+pub struct OsrmRouting {
+    endpoint_base: Url,
+    profile: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OsrmResponse {
+    code: String,
+    message: Option<String>,
+    #[serde(default)]
+    routes: Vec<OsrmRoute>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OsrmRoute {
+    geometry: GeoJsonLine,
+}
+
+impl OsrmRouting {
+    pub fn new(endpoint_base: &Url) -> Self {
+        OsrmRouting {
+            endpoint_base: endpoint_base.clone(),
+            profile: "foot".to_string(),
+        }
+    }
+
+    fn route_url(&self, trip: &Trip, alternates: usize) -> Result<Url, Box<dyn std::error::Error>> {
+        let places: Vec<String> = lon_lats(trip)
+            .iter()
+            .map(|[lon, lat]| format!("{},{}", lon, lat))
+            .collect();
+        let mut url =
+            self.endpoint_base
+                .join(&format!("/route/v1/{}/{}", self.profile, places.join(";")))?;
+        let alternatives = if alternates > 0 {
+            alternates.to_string()
+        } else {
+            "false".to_string()
+        };
+        url.query_pairs_mut()
+            .append_pair("overview", "full")
+            .append_pair("geometries", "geojson")
+            .append_pair("alternatives", &alternatives);
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl RoutingProvider for OsrmRouting {
+    #[instrument(skip(self, options))]
+    async fn routes(
+        &self,
+        trip: &Trip,
+        options: &RouteOptions,
+    ) -> Result<Vec<LineString>, Box<dyn std::error::Error>> {
+        if !options.avoid.is_empty() {
+            return Err("OSRM can't route around areas".into());
+        }
+        let url = self.route_url(trip, options.alternates)?;
+        let content = reqwest::get(url).await?.bytes().await?;
+        let response: OsrmResponse = serde_json::from_slice(&content)?;
+        if response.code != "Ok" {
+            return Err(format!(
+                "OSRM couldn't route, {}: {}",
+                response.code,
+                response.message.unwrap_or_default()
+            )
+            .into());
+        }
+
+        trace!("Converting {:?} routes", response.routes.len());
+
+        found(
+            response
+                .routes
+                .into_iter()
+                .map(|route| route.geometry.into())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::coord;
+
+    use super::*;
+
+    #[test]
+    fn places_are_in_the_path() {
+        let osrm = OsrmRouting::new(&Url::parse("http://localhost:5000").unwrap());
+        let trip = Trip {
+            origin: coord! { x: -3.19, y: 55.95 },
+            destination: coord! { x: -3.16, y: 55.94 },
+            via: vec![coord! { x: -3.2, y: 55.946 }],
+        };
+        assert_eq!(
+            osrm.route_url(&trip, 2).unwrap().as_str(),
+            "http://localhost:5000/route/v1/foot/-3.19,55.95;-3.2,55.946;-3.16,55.94\
+             ?overview=full&geometries=geojson&alternatives=2"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use ferrostar::{
+    models::{GeographicCoordinate, UserLocation, Waypoint, WaypointKind},
+    routing_adapters::{
+        osrm::OsrmResponseParser, valhalla::ValhallaHttpRequestGenerator, RouteRequest,
+        RouteRequestGenerator, RouteResponseParser,
+    },
+};
+use geo::{coord, Coord, LineString};
+use tracing::{instrument, trace};
+use url::Url;
+
+use super::{found, RouteOptions, RoutingProvider};
+use crate::trip::Trip;
+
+/// Routing by Valhalla, either self-hosted or Stadia Maps' hosted API
+pub struct ValhallaRouting {
+    route_url: Url,
+}
+
+impl ValhallaRouting {
+    /// A self-hosted Valhalla, with its route API at `/route`
+    pub fn new(endpoint_base: &Url) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(ValhallaRouting {
+            route_url: endpoint_base.join("/route")?,
+        })
+    }
+
+    pub fn stadia_maps(
+        api_key: &str,
+        endpoint_base: &Url,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut route_url = endpoint_base.join("/route/v1")?;
+        let authenticated_route_url = route_url
+            .query_pairs_mut()
+            .append_pair("api_key", api_key)
+            .finish();
+        Ok(ValhallaRouting {
+            route_url: authenticated_route_url.clone(),
+        })
+    }
+
+    /// The request for the trip, as ferrostar generates it with the options it
+    /// doesn't know about added
+    fn request(
+        &self,
+        trip: &Trip,
+        options: &RouteOptions,
+    ) -> Result<RouteRequest, Box<dyn std::error::Error>> {
+        let generator = ValhallaHttpRequestGenerator::new(
+            self.route_url.to_string().clone(),
+            "pedestrian".into(),
+            None,
+        );
+
+        let user_location = UserLocation {
+            coordinates: geographic(trip.origin),
+            horizontal_accuracy: 1.0,
+            course_over_ground: None,
+            timestamp: SystemTime::now(),
+            speed: None,
+        };
+        let waypoints: Vec<Waypoint> = trip
+            .via
+            .iter()
+            .map(|via| Waypoint {
+                coordinate: geographic(*via),
+                kind: WaypointKind::Via,
+            })
+            .chain(std::iter::once(Waypoint {
+                coordinate: geographic(trip.destination),
+                kind: WaypointKind::Break,
+            }))
+            .collect();
+
+        let RouteRequest::HttpPost { url, body, headers } =
+            generator.generate_request(user_location, waypoints)?;
+        // ferrostar's generator only takes costing options, so add any others to its request
+        let extra = extra_options(options);
+        let body = if extra.is_empty() {
+            body
+        } else {
+            let mut request: serde_json::Value = serde_json::from_slice(&body)?;
+            if let Some(request) = request.as_object_mut() {
+                request.extend(extra);
+            }
+            serde_json::to_vec(&request)?
+        };
+        Ok(RouteRequest::HttpPost { url, body, headers })
+    }
+}
+
+#[async_trait]
+impl RoutingProvider for ValhallaRouting {
+    #[instrument(skip(self, options))]
+    async fn routes(
+        &self,
+        trip: &Trip,
+        options: &RouteOptions,
+    ) -> Result<Vec<LineString>, Box<dyn std::error::Error>> {
+        let RouteRequest::HttpPost { url, body, headers } = self.request(trip, options)?;
+        let client = reqwest::Client::new();
+        let response = client
+            .post(url)
+            .body(body)
+            .headers(HeaderMap::try_from(&headers)?)
+            .send()
+            .await?;
+
+        let content = response.bytes().await?;
+        let routes = OsrmResponseParser::new(6).parse_response(content.to_vec())?;
+
+        trace!("Converting {:?} routes", routes.len());
+
+        let route_lines = routes
+            .iter()
+            .map(|route| {
+                LineString::new(
+                    route
+                        .geometry
+                        .iter()
+                        .map(|c| coord!(x: c.lng, y: c.lat))
+                        .collect(),
+                )
+            })
+            .collect();
+
+        found(route_lines)
+    }
+}
+
+/// The request options which ferrostar's generator doesn't know about
+fn extra_options(options: &RouteOptions) -> serde_json::Map<String, serde_json::Value> {
+    let mut extra = serde_json::Map::new();
+    if options.alternates > 0 {
+        extra.insert("alternates".to_string(), options.alternates.into());
+    }
+    if !options.avoid.is_empty() {
+        let rings: Vec<Vec<[f64; 2]>> = options
+            .avoid
+            .iter()
+            .map(|polygon| {
+                polygon
+                    .exterior()
+                    .coords()
+                    .map(|coord| [coord.x, coord.y])
+                    .collect()
+            })
+            .collect();
+        extra.insert("exclude_polygons".to_string(), serde_json::json!(rings));
+    }
+    extra
+}
+
+fn geographic(coord: Coord<f64>) -> GeographicCoordinate {
+    GeographicCoordinate {
+        lat: coord.y,
+        lng: coord.x,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::Rect;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn alternates_and_avoided_areas_are_in_the_body() {
+        let valhalla = ValhallaRouting::new(&Url::parse("http://localhost:8002").unwrap()).unwrap();
+        let trip = Trip {
+            origin: coord! { x: -3.19, y: 55.95 },
+            destination: coord! { x: -3.16, y: 55.94 },
+            via: vec![],
+        };
+        let avoid = Rect::new(coord! { x: -3.18, y: 55.94 }, coord! { x: -3.17, y: 55.95 });
+        let RouteRequest::HttpPost { url, body, .. } = valhalla
+            .request(
+                &trip,
+                &RouteOptions {
+                    alternates: 2,
+                    avoid: vec![avoid.to_polygon()],
+                },
+            )
+            .unwrap();
+
+        assert_eq!(url, "http://localhost:8002/route");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["costing"], json!("pedestrian"));
+        assert_eq!(body["alternates"], json!(2));
+        assert_eq!(
+            body["exclude_polygons"],
+            json!([[
+                [-3.18, 55.94],
+                [-3.18, 55.95],
+                [-3.17, 55.95],
+                [-3.17, 55.94],
+                [-3.18, 55.94]
+            ]])
+        );
+
+        let RouteRequest::HttpPost { body, .. } =
+            valhalla.request(&trip, &RouteOptions::default()).unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body.get("alternates").is_none());
+        assert!(body.get("exclude_polygons").is_none());
+    }
+}
@@ -0,0 +1,98 @@
+use geo::{GeodesicLength, LineString, MultiLineString};
+use serde::Serialize;
+
+/// How green a route is, with lengths in metres along the earth's surface
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct GreenScore {
+    pub length_metres: f64,
+    pub green_metres: f64,
+    /// the share of the route through greenery, from 0 to 100
+    pub green_percent: f64,
+    /// the longest continuous part of the route not through greenery
+    pub longest_not_green_metres: f64,
+    /// how many separate stretches of the route go through greenery
+    pub green_segments: usize,
+}
+
+impl GreenScore {
+    /// The score of `route`, given the parts of it through greenery, `green`, and
+    /// the rest of it, `outside`
+    pub fn new(
+        route: &LineString<f64>,
+        green: &MultiLineString<f64>,
+        outside: &MultiLineString<f64>,
+    ) -> Self {
+        let length_metres = route.geodesic_length();
+        let green_stretches = stretches(green);
+        let green_metres: f64 = green_stretches.iter().sum();
+        let green_percent = if length_metres > 0.0 {
+            (100.0 * green_metres / length_metres).min(100.0)
+        } else {
+            0.0
+        };
+        GreenScore {
+            length_metres,
+            green_metres,
+            green_percent,
+            longest_not_green_metres: stretches(outside).into_iter().fold(0.0, f64::max),
+            green_segments: green_stretches.len(),
+        }
+    }
+}
+
+/// The lengths of the continuous stretches of `lines`, joining consecutive lines
+/// where one ends at the start of the next, as clipping can split them there
+fn stretches(lines: &MultiLineString<f64>) -> Vec<f64> {
+    let mut lengths: Vec<f64> = vec![];
+    let mut previous_end = None;
+    for line in lines.iter().filter(|line| line.0.len() > 1) {
+        let length = line.geodesic_length();
+        match lengths.last_mut() {
+            Some(last) if previous_end == line.0.first() => *last += length,
+            _ => lengths.push(length),
+        }
+        previous_end = line.0.last();
+    }
+    lengths
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::line_string;
+
+    use super::*;
+
+    #[test]
+    fn green_stretches_are_measured() {
+        // a tenth of a degree along the equator is about 11km
+        let route = line_string![(x: 0., y: 0.), (x: 0.1, y: 0.)];
+        let green = MultiLineString::new(vec![
+            line_string![(x: 0.01, y: 0.), (x: 0.02, y: 0.)],
+            line_string![(x: 0.02, y: 0.), (x: 0.03, y: 0.)],
+            line_string![(x: 0.05, y: 0.), (x: 0.06, y: 0.)],
+        ]);
+        let outside = MultiLineString::new(vec![
+            line_string![(x: 0., y: 0.), (x: 0.01, y: 0.)],
+            line_string![(x: 0.03, y: 0.), (x: 0.05, y: 0.)],
+            line_string![(x: 0.06, y: 0.), (x: 0.1, y: 0.)],
+        ]);
+        let score = GreenScore::new(&route, &green, &outside);
+
+        let degree = route.geodesic_length() / 0.1;
+        assert!((score.length_metres - 11_132.0).abs() < 1.0);
+        assert!((score.green_metres - 0.03 * degree).abs() < 1e-6);
+        assert!((score.green_percent - 30.0).abs() < 1e-6);
+        assert!((score.longest_not_green_metres - 0.04 * degree).abs() < 1e-6);
+        assert_eq!(score.green_segments, 2);
+    }
+
+    #[test]
+    fn empty_routes_score_nothing() {
+        let score = GreenScore::new(
+            &LineString::new(vec![]),
+            &MultiLineString::new(vec![]),
+            &MultiLineString::new(vec![]),
+        );
+        assert_eq!(score, GreenScore::default());
+    }
+}
@@ -1,10 +1,10 @@
 use std::sync::Arc;
 
-use crate::{flatgeobuf::FgbSource, regions::Regions, routing::StadiaMapsRouting};
+use crate::{flatgeobuf::FgbSource, regions::Regions, routing::RoutingProvider};
 
 #[derive(Clone)]
 pub struct AppState {
     pub flatgeobuf: Arc<FgbSource>,
     pub regions: Arc<Regions>,
-    pub routing: Arc<StadiaMapsRouting>,
+    pub routing: Arc<dyn RoutingProvider>,
 }
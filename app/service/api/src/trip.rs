@@ -0,0 +1,133 @@
+use geo::{coord, Coord};
+use serde::Deserialize;
+
+/// The most via points a trip can have, to bound the work for the routing service
+pub const MAX_VIA: usize = 16;
+
+/// Query parameters for a trip, with places given as `lat,lon` and via points
+/// separated by `;`, e.g. `origin=55.95,-3.19&destination=55.94,-3.16&via=55.946,-3.2`
+#[derive(Deserialize, Debug)]
+pub struct TripQuery {
+    pub origin: String,
+    pub destination: String,
+    pub via: Option<String>,
+}
+
+/// Where a route starts and ends, and the points it must pass through on the way
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trip {
+    pub origin: Coord<f64>,
+    pub destination: Coord<f64>,
+    pub via: Vec<Coord<f64>>,
+}
+
+impl TryFrom<&TripQuery> for Trip {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(query: &TripQuery) -> Result<Self, Self::Error> {
+        let via = query
+            .via
+            .as_deref()
+            .filter(|via| !via.trim().is_empty())
+            .map(|via| {
+                via.split(';')
+                    .map(|place| parse_place("via", place))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+        if via.len() > MAX_VIA {
+            return Err(format!(
+                "at most {} via points are allowed, not {}",
+                MAX_VIA,
+                via.len()
+            )
+            .into());
+        }
+        Ok(Trip {
+            origin: parse_place("origin", &query.origin)?,
+            destination: parse_place("destination", &query.destination)?,
+            via,
+        })
+    }
+}
+
+/// A coordinate from `lat,lon`, which must be on the earth
+fn parse_place(name: &str, text: &str) -> Result<Coord<f64>, Box<dyn std::error::Error>> {
+    let (lat, lon) = text
+        .split_once(',')
+        .ok_or_else(|| format!("{} should be `lat,lon`, not '{}'", name, text))?;
+    let lat: f64 = lat
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} has an invalid latitude '{}'", name, lat))?;
+    let lon: f64 = lon
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} has an invalid longitude '{}'", name, lon))?;
+    if !(-90.0..=90.0).contains(&lat) {
+        return Err(format!("{} latitude {} is outside -90 to 90", name, lat).into());
+    }
+    if !(-180.0..=180.0).contains(&lon) {
+        return Err(format!("{} longitude {} is outside -180 to 180", name, lon).into());
+    }
+    Ok(coord! { x: lon, y: lat })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(origin: &str, destination: &str, via: Option<&str>) -> TripQuery {
+        TripQuery {
+            origin: origin.to_string(),
+            destination: destination.to_string(),
+            via: via.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn places_are_lat_lon() {
+        let trip = Trip::try_from(&query(
+            "55.95,-3.19",
+            " 55.94 , -3.16 ",
+            Some("55.946,-3.2;55.948,-3.18"),
+        ))
+        .unwrap();
+        assert_eq!(trip.origin, coord! { x: -3.19, y: 55.95 });
+        assert_eq!(trip.destination, coord! { x: -3.16, y: 55.94 });
+        assert_eq!(
+            trip.via,
+            vec![
+                coord! { x: -3.2, y: 55.946 },
+                coord! { x: -3.18, y: 55.948 }
+            ]
+        );
+
+        let no_via = Trip::try_from(&query("55.95,-3.19", "55.94,-3.16", Some(""))).unwrap();
+        assert!(no_via.via.is_empty());
+    }
+
+    #[test]
+    fn invalid_places_are_rejected() {
+        for (origin, destination, via) in [
+            ("55.95", "55.94,-3.16", None),
+            ("55.95,west", "55.94,-3.16", None),
+            ("95,-3.19", "55.94,-3.16", None),
+            ("55.95,-3.19", "55.94,181", None),
+            ("55.95,-3.19", "NaN,-3.16", None),
+            ("55.95,-3.19", "55.94,-3.16", Some("55.946,-3.2;")),
+        ] {
+            assert!(
+                Trip::try_from(&query(origin, destination, via)).is_err(),
+                "{} {} {:?}",
+                origin,
+                destination,
+                via
+            );
+        }
+
+        let too_many = vec!["55.946,-3.2"; MAX_VIA + 1].join(";");
+        assert!(Trip::try_from(&query("55.95,-3.19", "55.94,-3.16", Some(&too_many))).is_err());
+    }
+}
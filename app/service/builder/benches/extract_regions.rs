@@ -0,0 +1,152 @@
+use std::path::{Path, PathBuf};
+
+use builder::{
+    builder::{extract_regions, Sources},
+    filter::GreenTags,
+    nodes::NodeStoreKind,
+    region::{ElementKind, Region},
+    report::Report,
+    rings::{assemble_rings, polygons_from_rings},
+    validate::validate_regions,
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use geo::{Coord, Geometry, LineString, Polygon};
+use osmpbf::{Element, ElementReader};
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
+
+fn synthetic_city() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/synthetic_city_100.osm.pbf")
+}
+
+/// The reader `extract_regions` replaced, which decoded every blob of the file in
+/// each of three sequential passes and kept Node positions in a hash map
+fn three_pass_extract(path: &Path, green_tags: &GreenTags) -> Vec<Region> {
+    let tag_of = |tags: osmpbf::TagIter| {
+        let tag_set: HashSet<(&str, &str)> = tags.collect();
+        green_tags
+            .matching_tag(&tag_set)
+            .map(|(key, value)| format!("{}={}", key, value))
+    };
+    let mut direct_ways: HashMap<i64, String> = HashMap::default();
+    let mut relations: HashMap<i64, (String, Vec<i64>, Vec<i64>)> = HashMap::default();
+    ElementReader::from_path(path)
+        .unwrap()
+        .for_each(|element| match element {
+            Element::Way(way) => {
+                if let Some(tag) = tag_of(way.tags()) {
+                    direct_ways.insert(way.id(), tag);
+                }
+            }
+            Element::Relation(relation) => {
+                if let Some(tag) = tag_of(relation.tags()) {
+                    let (mut outer, mut inner) = (vec![], vec![]);
+                    for member in relation.members() {
+                        match member.role() {
+                            Ok("outer") => outer.push(member.member_id),
+                            Ok("inner") => inner.push(member.member_id),
+                            _ => (),
+                        }
+                    }
+                    relations.insert(relation.id(), (tag, outer, inner));
+                }
+            }
+            _ => (),
+        })
+        .unwrap();
+
+    let mut allowed_ways: HashSet<i64> = direct_ways.keys().copied().collect();
+    for (_, outer, inner) in relations.values() {
+        allowed_ways.extend(outer.iter().chain(inner.iter()));
+    }
+    let mut refs_for_ways: HashMap<i64, Vec<i64>> = HashMap::default();
+    ElementReader::from_path(path)
+        .unwrap()
+        .for_each(|element| {
+            if let Element::Way(way) = element {
+                if allowed_ways.contains(&way.id()) {
+                    refs_for_ways.insert(way.id(), way.refs().collect());
+                }
+            }
+        })
+        .unwrap();
+
+    let allowed_refs: HashSet<i64> = refs_for_ways.values().flatten().copied().collect();
+    let mut coords: HashMap<i64, Coord> = HashMap::default();
+    ElementReader::from_path(path)
+        .unwrap()
+        .for_each(|element| {
+            let (id, coord) = match element {
+                Element::DenseNode(node) => (node.id(), Coord::from((node.lon(), node.lat()))),
+                Element::Node(node) => (node.id(), Coord::from((node.lon(), node.lat()))),
+                _ => return,
+            };
+            if allowed_refs.contains(&id) {
+                coords.insert(id, coord);
+            }
+        })
+        .unwrap();
+
+    let ring = |refs: &[i64]| -> LineString {
+        refs.iter().filter_map(|r| coords.get(r)).copied().collect()
+    };
+    let rings = |ways: &[i64]| -> Vec<LineString> {
+        let segments = ways
+            .iter()
+            .filter_map(|way| refs_for_ways.get(way))
+            .cloned()
+            .collect();
+        assemble_rings(segments)
+            .0
+            .iter()
+            .map(|refs| ring(refs))
+            .collect()
+    };
+    let mut regions = vec![];
+    for (id, tag) in direct_ways {
+        if let Some(refs) = refs_for_ways.get(&id) {
+            regions.push(Region {
+                geometry: Geometry::Polygon(Polygon::new(ring(refs), vec![])),
+                osm_id: id,
+                osm_type: ElementKind::Way,
+                tag,
+            });
+        }
+    }
+    for (id, (tag, outer, inner)) in relations {
+        regions.push(Region {
+            geometry: Geometry::MultiPolygon(polygons_from_rings(rings(&outer), rings(&inner))),
+            osm_id: id,
+            osm_type: ElementKind::Relation,
+            tag,
+        });
+    }
+    validate_regions(regions, &mut Report::default())
+}
+
+fn extract_regions_benchmark(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = synthetic_city();
+    let green_tags = GreenTags::default();
+
+    c.bench_function("three_pass_extract synthetic_city_100", |b| {
+        b.iter(|| three_pass_extract(&path, &green_tags))
+    });
+    for kind in NodeStoreKind::ALL {
+        c.bench_function(
+            &format!("extract_regions synthetic_city_100 {:?}", kind),
+            |b| {
+                b.iter(|| {
+                    let nodes = kind.create(Some(dir.path())).unwrap();
+                    extract_regions(&path, &green_tags, Sources::All, nodes).unwrap()
+                })
+            },
+        );
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = extract_regions_benchmark
+}
+criterion_main!(benches);
@@ -1,10 +1,18 @@
 use std::{fs::File, io::BufWriter, path::PathBuf};
 
-use builder::builder::extract_regions;
-use clap::Parser;
-use flatgeobuf::{FgbWriter, GeometryType};
-use geozero::{geojson::GeoJsonWriter, GeozeroGeometry};
-use tracing::{debug, info, trace};
+use builder::{
+    builder::{extract_regions, Sources},
+    dissolve::dissolve,
+    filter::{GreenTagRules, GreenTags},
+    nodes::NodeStoreKind,
+    region::{process_regions, AreaWriter},
+    report::Report,
+};
+use clap::{Parser, ValueEnum};
+use core_geo::{DEGRADED_PROPERTY, DISSOLVED_LAYER};
+use flatgeobuf::{ColumnType, FgbWriter, GeometryType};
+use geozero::geojson::GeoJsonWriter;
+use tracing::{debug, info, warn};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 
 /// Extract features from Openstreetmap and convert into single output file
@@ -22,6 +30,99 @@ struct Args {
     /// output flatgeobuf `.fgb` file
     #[arg(short, long)]
     fgb: Option<PathBuf>,
+
+    /// `.toml` or `.json` file of green tag rules (built-in rules are used if not given)
+    #[arg(short, long)]
+    tags: Option<PathBuf>,
+
+    /// profile to use from the green tag rules file
+    #[arg(long, default_value = "default", requires = "tags")]
+    profile: String,
+
+    /// which OSM elements to take green areas from; each feature records
+    /// where it came from in its `osm_type` property
+    #[arg(long, value_enum, default_value_t = SourcesArg::All)]
+    sources: SourcesArg,
+
+    /// where to keep Node positions while assembling Ways; `sorted` or `mmap`
+    /// keep memory use down for continent-sized extracts, though the ids of the
+    /// Nodes which wanted Ways refer to are still held in memory
+    #[arg(long, value_enum, default_value_t = NodeStoreArg::Hash)]
+    node_store: NodeStoreArg,
+
+    /// union overlapping areas together and write only the dissolved layer, without
+    /// per-element properties, which the API recognises by its layer name and so
+    /// skips unioning at query time; each area's `degraded` property says whether a
+    /// fallback was needed to union it
+    #[arg(long)]
+    dissolve: bool,
+
+    /// dissolve in a grid of tiles of this size, in degrees, which bounds the work
+    /// done in any one union; use for country-sized extracts
+    #[arg(long, requires = "dissolve", value_parser = parse_tile_size)]
+    dissolve_tile_size: Option<f64>,
+
+    /// directory for the `mmap` node store's temporary file (defaults to the
+    /// system temporary directory)
+    #[arg(long)]
+    node_store_dir: Option<PathBuf>,
+}
+
+/// A tile size, which must be finite and greater than zero
+fn parse_tile_size(s: &str) -> Result<f64, String> {
+    let tile_size: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if tile_size.is_finite() && tile_size > 0.0 {
+        Ok(tile_size)
+    } else {
+        Err(format!("{} isn't a finite size greater than zero", s))
+    }
+}
+
+/// The command line names for `Sources`
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SourcesArg {
+    /// only Ways which are themselves tagged as green
+    Direct,
+    /// only Ways which are members of Relations tagged as green
+    Relations,
+    /// both of the above
+    All,
+}
+
+impl From<SourcesArg> for Sources {
+    fn from(sources: SourcesArg) -> Self {
+        match sources {
+            SourcesArg::Direct => Sources::Direct,
+            SourcesArg::Relations => Sources::Relations,
+            SourcesArg::All => Sources::All,
+        }
+    }
+}
+
+/// The command line names for `NodeStoreKind`
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum NodeStoreArg {
+    /// hash map in memory; fast, but around 40 bytes per stored Node
+    Hash,
+    /// array in memory indexed by Node id; 8 bytes per id up to the largest id
+    /// stored, so best for small extracts with low ids
+    Dense,
+    /// sorted array in memory; 16 bytes per stored Node
+    Sorted,
+    /// array indexed by Node id in a sparse temporary file, which the OS pages
+    /// in and out
+    Mmap,
+}
+
+impl From<NodeStoreArg> for NodeStoreKind {
+    fn from(kind: NodeStoreArg) -> Self {
+        match kind {
+            NodeStoreArg::Hash => NodeStoreKind::Hash,
+            NodeStoreArg::Dense => NodeStoreKind::Dense,
+            NodeStoreArg::Sorted => NodeStoreKind::Sorted,
+            NodeStoreArg::Mmap => NodeStoreKind::Mmap,
+        }
+    }
 }
 
 fn setup_tracing_and_logging(fmt_filter: EnvFilter) -> Result<(), Box<dyn std::error::Error>> {
@@ -37,43 +138,113 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
     debug!("{:?}", args);
 
-    let mut geoms = vec![];
+    let rules = args
+        .tags
+        .as_ref()
+        .map(|path| GreenTagRules::from_path(path))
+        .transpose()?;
+    let green_tags = match rules.as_ref() {
+        Some(rules) => {
+            info!("using profile '{}' from {:?}", args.profile, args.tags);
+            rules.profile(&args.profile)?
+        }
+        None => GreenTags::default(),
+    };
+
+    let mut regions = vec![];
+    let mut report = Report::default();
 
     info!("processing input files: {:?}", args.pbf);
     for input in args.pbf {
-        let collection = extract_regions(&input).expect("failed when extracting regions");
-        geoms.push(geo::geometry::Geometry::GeometryCollection(collection));
+        let (input_regions, input_report) = extract_regions(
+            &input,
+            &green_tags,
+            args.sources.into(),
+            NodeStoreKind::from(args.node_store).create(args.node_store_dir.as_deref())?,
+        )
+        .expect("failed when extracting regions");
+        regions.extend(input_regions);
+        report.merge(input_report);
+    }
+    if report.is_empty() {
+        info!("{}", report);
+    } else {
+        warn!("repaired or skipped elements:{}", report);
+    }
+
+    if args.dissolve {
+        let mut gout = args
+            .geojson
+            .map(|s| -> std::io::Result<_> {
+                info!("writing dissolved geojson to {:?}", s);
+                Ok(GeoJsonWriter::new(BufWriter::new(File::create(s)?)))
+            })
+            .transpose()?;
+        let mut fgb = match args.fgb {
+            Some(s) => {
+                info!("writing dissolved flatgeobuf to {:?}", s);
+                let mut fgb = FgbWriter::create(DISSOLVED_LAYER, GeometryType::Polygon)?;
+                fgb.add_column(DEGRADED_PROPERTY, ColumnType::Bool, |_, _| {});
+                Some((s, fgb))
+            }
+            None => None,
+        };
+        let mut geojson_writer = gout.as_mut().map(AreaWriter::begin).transpose()?;
+        let mut fgb_writer = fgb
+            .as_mut()
+            .map(|(_, fgb)| AreaWriter::begin(fgb))
+            .transpose()?;
+
+        let mut dissolve_report = Report::default();
+        let areas = dissolve(
+            &regions,
+            args.dissolve_tile_size,
+            &mut dissolve_report,
+            |geometry, degraded| {
+                if let Some(writer) = geojson_writer.as_mut() {
+                    writer.write(&geometry, degraded)?;
+                }
+                if let Some(writer) = fgb_writer.as_mut() {
+                    writer.write(&geometry, degraded)?;
+                }
+                Ok(())
+            },
+        )?;
+        if !dissolve_report.is_empty() {
+            warn!("fallbacks needed while dissolving:{}", dissolve_report);
+        }
+        info!("dissolved {} regions into {} areas", regions.len(), areas);
+
+        if let Some(writer) = geojson_writer {
+            writer.finish()?;
+        }
+        if let Some(writer) = fgb_writer {
+            writer.finish()?;
+        }
+        if let Some((s, fgb)) = fgb {
+            let mut fout = BufWriter::new(File::create(s)?);
+            fgb.write(&mut fout)?;
+        }
+
+        return Ok(());
     }
 
     if let Some(s) = args.geojson {
         info!("writing geojson to {:?}", s);
         let fout = BufWriter::new(File::create(s)?);
         let mut gout = GeoJsonWriter::new(fout);
-        for geom in geoms.iter() {
-            geom.process_geom(&mut gout)?;
-        }
+        process_regions(&regions, &mut gout)?;
     }
 
     if let Some(s) = args.fgb {
         info!("writing flatgeobuf to {:?}", s);
-        let mut fgb = FgbWriter::create("all", GeometryType::Polygon)?;
-        let mut geom_added_count = 0;
-        debug!("adding geoms");
-        for geom in geoms.iter() {
-            match geom {
-                geo::geometry::Geometry::GeometryCollection(c) => {
-                    for geom in c.into_iter() {
-                        trace!("adding geom, {:?}", geom);
-                        fgb.add_feature_geom(geom.clone(), |_| {})?;
-                        geom_added_count += 1;
-                    }
-                }
-                _ => {
-                    todo!("only handling GeometryCollection for now");
-                }
-            }
-        }
-        debug!("added {} geoms", geom_added_count);
+        let mut fgb = FgbWriter::create("all", GeometryType::Unknown)?;
+        fgb.add_column("osm_id", ColumnType::Long, |_, _| {});
+        fgb.add_column("osm_type", ColumnType::String, |_, _| {});
+        fgb.add_column("tag", ColumnType::String, |_, _| {});
+        debug!("adding {} regions", regions.len());
+        process_regions(&regions, &mut fgb)?;
+        debug!("added {} regions", regions.len());
 
         let mut fout = BufWriter::new(File::create(s)?);
         fgb.write(&mut fout)?;
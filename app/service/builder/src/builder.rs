@@ -1,55 +1,124 @@
 use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
 use std::{
+    collections::BTreeSet,
     fmt::{Display, Formatter},
+    hash::Hash,
     path::Path,
+    sync::mpsc,
+    thread,
 };
 
-use geo::geometry::{Coord, Geometry, GeometryCollection, LineString, Polygon};
-use osmpbf::{Element, ElementReader, Relation, Way};
-use tracing::{debug, instrument};
+use geo::geometry::{Coord, Geometry, LineString, Polygon};
+use indicatif::ProgressBar;
+use osmpbf::{PrimitiveBlock, Relation, Way};
+use tracing::{debug, info, instrument};
 
 use crate::filter::GreenTags;
+use crate::nodes::{Location, NodeStore};
+use crate::pbf::{IdRanges, IndexedPbf};
 use crate::progress::progress_bar;
+use crate::region::{ElementKind, Region};
+use crate::report::{Reason, Report};
+use crate::rings::{assemble_rings, polygons_from_rings};
+use crate::validate::validate_regions;
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+/// Which OSM elements green areas are taken from
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Sources {
+    /// only Ways which are themselves tagged as green
+    Direct,
+    /// only Ways which are members of Relations tagged as green
+    Relations,
+    /// both of the above
+    #[default]
+    All,
+}
+
+impl Sources {
+    fn includes_direct(&self) -> bool {
+        matches!(self, Sources::Direct | Sources::All)
+    }
+
+    fn includes_relations(&self) -> bool {
+        matches!(self, Sources::Relations | Sources::All)
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 struct WayId(i64);
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 struct RefId(i64);
 
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+struct RelationId(i64);
+
+struct RelationMembers {
+    tag: String,
+    outer: Vec<WayId>,
+    inner: Vec<WayId>,
+}
+
+impl RelationMembers {
+    fn ways(&self) -> impl Iterator<Item = &WayId> {
+        self.outer.iter().chain(self.inner.iter())
+    }
+}
+
 #[derive(Default)]
 struct FilterStage {
-    ways: HashSet<WayId>,
+    ways: HashMap<WayId, String>,
+    relations: HashMap<RelationId, RelationMembers>,
+    refs_for_ways: HashMap<WayId, Vec<RefId>>,
     direct_ways_count: usize,
     ways_via_relation_count: usize,
 }
 
 impl FilterStage {
-    fn append_way(&mut self, way: &Way) {
-        self.ways.insert(WayId(way.id()));
+    fn append_way(&mut self, way: &Way, tag: String) {
+        let way_id = WayId(way.id());
+        self.ways.insert(way_id, tag);
+        self.refs_for_ways
+            .insert(way_id, way.refs().map(RefId).collect());
         self.direct_ways_count += 1;
     }
 
-    fn append_relation(&mut self, relation: &Relation) {
+    fn append_relation(&mut self, relation: &Relation, tag: String) {
         let tag_set: HashSet<(&str, &str)> = relation.tags().collect();
         if tag_set.contains(&("type", "multipolygon")) {
-            if let Some(outer_way) = relation.members().find(|m| {
-                if m.member_type == osmpbf::RelMemberType::Way {
-                    if let Ok("outer") = m.role() {
-                        return true;
+            let mut members = RelationMembers {
+                tag,
+                outer: vec![],
+                inner: vec![],
+            };
+            for member in relation.members() {
+                if member.member_type == osmpbf::RelMemberType::Way {
+                    match member.role() {
+                        Ok("outer") => members.outer.push(WayId(member.member_id)),
+                        Ok("inner") => members.inner.push(WayId(member.member_id)),
+                        _ => (),
                     }
                 }
-                false
-            }) {
-                self.ways.insert(WayId(outer_way.member_id));
-                self.ways_via_relation_count += 1;
+            }
+            if !members.outer.is_empty() {
+                self.ways_via_relation_count += members.ways().count();
+                self.relations.insert(RelationId(relation.id()), members);
             }
         }
     }
 
+    fn merge(mut self, other: FilterStage) -> FilterStage {
+        self.ways.extend(other.ways);
+        self.relations.extend(other.relations);
+        self.refs_for_ways.extend(other.refs_for_ways);
+        self.direct_ways_count += other.direct_ways_count;
+        self.ways_via_relation_count += other.ways_via_relation_count;
+        self
+    }
+
     fn into_pending_stage(self) -> PendingStage {
-        PendingStage::new(self.ways)
+        PendingStage::new(self.ways, self.relations, self.refs_for_ways)
     }
 }
 
@@ -57,164 +126,403 @@ impl Display for FilterStage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "FilterStage, #direct: {}, #via_relation: {}, #total: {}",
+            "FilterStage, #direct: {}, #via_relation: {}, #relations: {}",
             self.direct_ways_count,
             self.ways_via_relation_count,
-            self.ways.len()
+            self.relations.len()
         )
     }
 }
 
 struct PendingStage {
-    allowed_ways: HashSet<WayId>,
-    allowed_refs: HashSet<RefId>,
-    refs_for_ways: Vec<Vec<RefId>>,
+    direct_ways: HashMap<WayId, String>,
+    relations: HashMap<RelationId, RelationMembers>,
+    pending_ways: BTreeSet<WayId>,
+    refs_for_ways: HashMap<WayId, Vec<RefId>>,
 }
 
 impl PendingStage {
-    fn new(allowed_ways: HashSet<WayId>) -> Self {
+    fn new(
+        direct_ways: HashMap<WayId, String>,
+        relations: HashMap<RelationId, RelationMembers>,
+        refs_for_ways: HashMap<WayId, Vec<RefId>>,
+    ) -> Self {
+        // Ways usually come before Relations in a file, so any Ways we only know
+        // about via a Relation have to be picked up afterwards
+        let pending_ways = relations
+            .values()
+            .flat_map(RelationMembers::ways)
+            .filter(|way_id| !refs_for_ways.contains_key(way_id))
+            .cloned()
+            .collect();
         PendingStage {
-            allowed_ways,
-            allowed_refs: HashSet::default(),
-            refs_for_ways: Vec::default(),
+            direct_ways,
+            relations,
+            pending_ways,
+            refs_for_ways,
         }
     }
 }
 
 impl Display for PendingStage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "PendingStage, saw #ways: {}", self.refs_for_ways.len())
+        write!(
+            f,
+            "PendingStage, saw #ways: {}, #pending: {}",
+            self.refs_for_ways.len(),
+            self.pending_ways.len()
+        )
     }
 }
 
 impl PendingStage {
-    fn append_way(&mut self, way: &Way) {
-        let way_id = WayId(way.id());
-        if self.allowed_ways.contains(&way_id) {
-            let mut refs_for_way: Vec<RefId> = vec![];
-            way.refs().for_each(|r| {
-                let ref_id = RefId(r);
-                refs_for_way.push(ref_id);
-                self.allowed_refs.insert(ref_id);
-            });
-            self.refs_for_ways.push(refs_for_way);
+    fn wants(&self, ranges: &IdRanges) -> bool {
+        ranges.ways.as_ref().is_some_and(|ways| {
+            self.pending_ways
+                .range(WayId(*ways.start())..=WayId(*ways.end()))
+                .next()
+                .is_some()
+        })
+    }
+
+    fn find_ways(&self, block: &PrimitiveBlock) -> HashMap<WayId, Vec<RefId>> {
+        let mut found = HashMap::default();
+        for group in block.groups() {
+            for way in group.ways() {
+                let way_id = WayId(way.id());
+                if self.pending_ways.contains(&way_id) {
+                    found.insert(way_id, way.refs().map(RefId).collect());
+                }
+            }
         }
+        found
     }
 
-    fn into_assign_stage(self) -> AssignStage {
+    fn merge(&mut self, found: HashMap<WayId, Vec<RefId>>) {
+        self.refs_for_ways.extend(found);
+    }
+
+    fn into_assign_stage(self, nodes: Box<dyn NodeStore>) -> AssignStage {
+        let mut allowed_refs: Vec<RefId> = self.refs_for_ways.values().flatten().cloned().collect();
+        allowed_refs.sort_unstable();
+        allowed_refs.dedup();
+        allowed_refs.shrink_to_fit();
         AssignStage {
-            allowed_refs: self.allowed_refs,
+            direct_ways: self.direct_ways,
+            relations: self.relations,
+            allowed_refs,
             refs_for_ways: self.refs_for_ways,
-            coords_for_refs: HashMap::default(),
+            nodes,
         }
     }
 }
 
 struct AssignStage {
-    allowed_refs: HashSet<RefId>,
-    refs_for_ways: Vec<Vec<RefId>>,
-    coords_for_refs: HashMap<RefId, Coord>,
+    direct_ways: HashMap<WayId, String>,
+    relations: HashMap<RelationId, RelationMembers>,
+    /// sorted, so that it is compact and can be range-checked
+    allowed_refs: Vec<RefId>,
+    refs_for_ways: HashMap<WayId, Vec<RefId>>,
+    nodes: Box<dyn NodeStore>,
 }
 
 impl AssignStage {
-    fn add_coord_for_ref_id(&mut self, ref_id: RefId, coord: &Coord) {
-        if self.allowed_refs.contains(&ref_id) {
-            self.coords_for_refs.insert(ref_id, *coord);
+    fn wants(&self, ranges: &IdRanges) -> bool {
+        ranges.nodes.as_ref().is_some_and(|nodes| {
+            let first = self
+                .allowed_refs
+                .partition_point(|ref_id| ref_id.0 < *nodes.start());
+            self.allowed_refs
+                .get(first)
+                .is_some_and(|ref_id| ref_id.0 <= *nodes.end())
+        })
+    }
+
+    /// Decode `blobs` in parallel and put the locations of allowed Nodes in the
+    /// store. Each blob's locations are handed to a single writer thread, which
+    /// owns the store, so decoding never waits on it; only a few blobs' worth are
+    /// ever waiting to be inserted, so memory use is bounded by the store.
+    fn assign_locations(
+        &mut self,
+        pbf: &mut IndexedPbf,
+        blobs: Vec<usize>,
+        bar: &ProgressBar,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let allowed_refs = &self.allowed_refs;
+        let nodes = &mut self.nodes;
+        let (sender, receiver) =
+            mpsc::sync_channel::<Vec<(i64, Location)>>(rayon::current_num_threads());
+        thread::scope(|scope| {
+            let writer = scope.spawn(move || {
+                for found in receiver {
+                    nodes.insert_all(&found)?;
+                }
+                nodes.finish();
+                Ok::<_, std::io::Error>(())
+            });
+            let decoded = pbf.par_map_reduce(
+                blobs,
+                |block| {
+                    // sending only fails once the writer has stopped on an error,
+                    // which is returned below
+                    let _ = sender.send(find_locations(allowed_refs, block));
+                    bar.inc(1);
+                },
+                || (),
+                |_, _| (),
+            );
+            drop(sender);
+            writer.join().expect("node store writer panicked")?;
+            decoded
+        })
+    }
+
+    /// Coords for `ref_ids`, along with whether any had to be dropped because their
+    /// Nodes aren't in the file (as happens at the edges of clipped extracts); `None`
+    /// if too few are left to close a ring with
+    fn ring(&self, ref_ids: &[RefId]) -> Option<(LineString<f64>, bool)> {
+        let mut coords: Vec<Coord> = ref_ids
+            .iter()
+            .filter_map(|ref_id| self.nodes.get(ref_id.0))
+            .map(|location| location.coord())
+            .collect();
+        if coords.len() == ref_ids.len() {
+            return Some((coords.into(), false));
+        }
+        coords.dedup();
+        if let (Some(first), Some(last)) = (coords.first(), coords.last()) {
+            if first != last {
+                coords.push(*first);
+            }
+        }
+        if coords.len() < 4 {
+            None
+        } else {
+            Some((coords.into(), true))
+        }
+    }
+
+    /// Rings joined up from the Ways in `way_ids`, adding to `issues` the reasons
+    /// for any which were repaired or left out
+    fn rings(&self, way_ids: &[WayId], issues: &mut BTreeSet<Reason>) -> Vec<LineString<f64>> {
+        let segments: Vec<Vec<RefId>> = way_ids
+            .iter()
+            .filter_map(|way_id| self.refs_for_ways.get(way_id))
+            .cloned()
+            .collect();
+        if segments.len() < way_ids.len() {
+            issues.insert(Reason::MissingMemberWays);
+        }
+        let (rings, dropped) = assemble_rings(segments);
+        if dropped > 0 {
+            issues.insert(Reason::UnclosedRingDropped);
         }
+        rings
+            .iter()
+            .filter_map(|ref_ids| match self.ring(ref_ids) {
+                Some((ring, dropped)) => {
+                    if dropped {
+                        issues.insert(Reason::MissingNodesDropped);
+                    }
+                    Some(ring)
+                }
+                None => {
+                    issues.insert(Reason::MissingNodesSkipped);
+                    None
+                }
+            })
+            .collect()
     }
 
-    fn into_geometry(self) -> Vec<Geometry<f64>> {
-        let mut geometry = vec![];
-        let bar = progress_bar(self.refs_for_ways.len() as u64);
-        for ref_ids in self.refs_for_ways.into_iter() {
-            let coords = ref_ids
-                .into_iter()
-                .map(|ref_id| self.coords_for_refs.get(&ref_id).unwrap())
-                .cloned()
-                .collect::<Vec<Coord>>();
-            let polygon = Polygon::new(LineString::from(coords), vec![]);
-            geometry.push(Geometry::Polygon(polygon));
+    /// The regions, Ways first and then Relations, each in order of id, so that
+    /// the output is the same from one build to the next
+    fn into_regions(self, report: &mut Report) -> Vec<Region> {
+        let mut regions = vec![];
+        let bar = progress_bar((self.direct_ways.len() + self.relations.len()) as u64);
+        let mut direct_ways: Vec<_> = self.direct_ways.iter().collect();
+        direct_ways.sort_unstable_by_key(|(way_id, _)| **way_id);
+        let mut relations: Vec<_> = self.relations.iter().collect();
+        relations.sort_unstable_by_key(|(relation_id, _)| **relation_id);
+        for (way_id, tag) in direct_ways {
+            if let Some(ref_ids) = self.refs_for_ways.get(way_id) {
+                match self.ring(ref_ids) {
+                    Some((ring, dropped)) => {
+                        if dropped {
+                            report.add(Reason::MissingNodesDropped, ElementKind::Way, way_id.0);
+                        } else if ref_ids.first() != ref_ids.last() {
+                            // `Polygon::new` closes it
+                            report.add(Reason::UnclosedRingClosed, ElementKind::Way, way_id.0);
+                        }
+                        regions.push(Region {
+                            geometry: Geometry::Polygon(Polygon::new(ring, vec![])),
+                            osm_id: way_id.0,
+                            osm_type: ElementKind::Way,
+                            tag: tag.clone(),
+                        });
+                    }
+                    None => report.add(Reason::MissingNodesSkipped, ElementKind::Way, way_id.0),
+                }
+            }
+            bar.inc(1);
+        }
+        for (relation_id, members) in relations {
+            let mut issues = BTreeSet::new();
+            let outer = self.rings(&members.outer, &mut issues);
+            if outer.is_empty() {
+                debug!("no closed outer rings found for {:?}", relation_id);
+                issues.insert(Reason::NoClosedOuterRing);
+            } else {
+                let inner = self.rings(&members.inner, &mut issues);
+                let multi_polygon = polygons_from_rings(outer, inner);
+                regions.push(Region {
+                    geometry: Geometry::MultiPolygon(multi_polygon),
+                    osm_id: relation_id.0,
+                    osm_type: ElementKind::Relation,
+                    tag: members.tag.clone(),
+                });
+            }
+            for reason in issues {
+                report.add(reason, ElementKind::Relation, relation_id.0);
+            }
             bar.inc(1);
         }
         bar.finish();
-        geometry
+        regions
     }
 }
 
 impl Display for AssignStage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "AssignStage {{ ways: {} }}", self.refs_for_ways.len())
+        write!(
+            f,
+            "AssignStage {{ ways: {}, relations: {}, nodes: {} }}",
+            self.refs_for_ways.len(),
+            self.relations.len(),
+            self.nodes.len()
+        )
+    }
+}
+
+/// The locations of the Nodes in `block` which are in the sorted `allowed_refs`
+fn find_locations(allowed_refs: &[RefId], block: &PrimitiveBlock) -> Vec<(i64, Location)> {
+    let is_allowed = |id: i64| allowed_refs.binary_search(&RefId(id)).is_ok();
+    let mut found = vec![];
+    for group in block.groups() {
+        for dense_node in group.dense_nodes() {
+            if is_allowed(dense_node.id()) {
+                let location =
+                    Location::new(dense_node.decimicro_lon(), dense_node.decimicro_lat());
+                found.push((dense_node.id(), location));
+            }
+        }
+        for node in group.nodes() {
+            if is_allowed(node.id()) {
+                found.push((
+                    node.id(),
+                    Location::new(node.decimicro_lon(), node.decimicro_lat()),
+                ));
+            }
+        }
+    }
+    found
+}
+
+fn merge_maps<K: Eq + Hash, V>(mut a: HashMap<K, V>, mut b: HashMap<K, V>) -> HashMap<K, V> {
+    if a.len() < b.len() {
+        std::mem::swap(&mut a, &mut b);
     }
+    a.extend(b);
+    a
 }
 
-#[instrument]
+/// Extract green regions from `osmpbf_path`, keeping Node positions in `nodes`
+/// while assembling them, along with a report of any elements which had to be
+/// repaired or skipped
+#[instrument(skip(green_tags, nodes))]
 pub fn extract_regions(
     osmpbf_path: &Path,
-) -> Result<GeometryCollection<f64>, Box<dyn std::error::Error>> {
-    debug!("Filtering Ways");
-    let mut filter_stage = FilterStage::default();
+    green_tags: &GreenTags,
+    sources: Sources,
+    nodes: Box<dyn NodeStore>,
+) -> Result<(Vec<Region>, Report), Box<dyn std::error::Error>> {
+    let mut pbf = IndexedPbf::open(osmpbf_path)?;
 
-    let green_tags = GreenTags::default();
-    let way_filter = |way: &Way| {
-        let tag_set: HashSet<(&str, &str)> = way.tags().collect();
-        green_tags.filter(tag_set)
+    debug!("Filtering Ways");
+    let matching_tag = |tags: osmpbf::TagIter| {
+        let tag_set: HashSet<(&str, &str)> = tags.collect();
+        green_tags
+            .matching_tag(&tag_set)
+            .map(|(key, value)| format!("{}={}", key, value))
     };
-    let element_reader = ElementReader::from_path(osmpbf_path)?;
-    let mut total_elements = 0u64;
-    element_reader.for_each(|element| {
-        if let Element::Way(way) = element {
-            if way_filter(&way) {
-                filter_stage.append_way(&way);
-            }
-        } else if let Element::Relation(relation) = element {
-            let tag_set: HashSet<(&str, &str)> = relation.tags().collect();
-            if green_tags.filter(tag_set) {
-                filter_stage.append_relation(&relation);
+    let blobs = pbf.select(|_| true);
+    let filter_stage_bar = progress_bar(blobs.len() as u64);
+    let filter_stage = pbf.par_map_reduce(
+        blobs,
+        |block| {
+            let mut filter_stage = FilterStage::default();
+            for group in block.groups() {
+                if sources.includes_direct() {
+                    for way in group.ways() {
+                        if let Some(tag) = matching_tag(way.tags()) {
+                            filter_stage.append_way(&way, tag);
+                        }
+                    }
+                }
+                if sources.includes_relations() {
+                    for relation in group.relations() {
+                        if let Some(tag) = matching_tag(relation.tags()) {
+                            filter_stage.append_relation(&relation, tag);
+                        }
+                    }
+                }
             }
-        }
-        total_elements += 1;
-    })?;
-    debug!("Filtered: {}", filter_stage);
+            filter_stage_bar.inc(1);
+            filter_stage
+        },
+        FilterStage::default,
+        FilterStage::merge,
+    )?;
+    filter_stage_bar.finish();
+    info!("Filtered: {}", filter_stage);
 
     debug!("Collecting");
     let mut pending_stage = filter_stage.into_pending_stage();
-    let pending_stage_bar = progress_bar(total_elements);
-    let element_reader = ElementReader::from_path(osmpbf_path)?;
-    element_reader.for_each(|element| {
-        if let Element::Way(way) = element {
-            pending_stage.append_way(&way);
-        }
-        pending_stage_bar.inc(1);
-    })?;
+    let blobs = pbf.select(|ranges| pending_stage.wants(ranges));
+    debug!("Reading {} of {} blobs", blobs.len(), pbf.len());
+    let pending_stage_bar = progress_bar(blobs.len() as u64);
+    let found = pbf.par_map_reduce(
+        blobs,
+        |block| {
+            let found = pending_stage.find_ways(block);
+            pending_stage_bar.inc(1);
+            found
+        },
+        HashMap::default,
+        merge_maps,
+    )?;
+    pending_stage.merge(found);
     pending_stage_bar.finish();
     debug!("Collected: {}", pending_stage);
 
     debug!("Assigning Coords");
-    let mut assign_stage = pending_stage.into_assign_stage();
+    let mut assign_stage = pending_stage.into_assign_stage(nodes);
     debug!("Created stage");
-    let assign_stage_bar = progress_bar(total_elements);
-    let element_reader = ElementReader::from_path(osmpbf_path)?;
-    element_reader.for_each(|element| {
-        match element {
-            Element::DenseNode(dense_node) => {
-                let coord = Coord::from((dense_node.lon(), dense_node.lat()));
-                assign_stage.add_coord_for_ref_id(RefId(dense_node.id()), &coord);
-            }
-            Element::Node(node) => {
-                let coord = Coord::from((node.lon(), node.lat()));
-                assign_stage.add_coord_for_ref_id(RefId(node.id()), &coord);
-            }
-            _ => (),
-        };
-        assign_stage_bar.inc(1);
-    })?;
+    let blobs = pbf.select(|ranges| assign_stage.wants(ranges));
+    debug!("Reading {} of {} blobs", blobs.len(), pbf.len());
+    let assign_stage_bar = progress_bar(blobs.len() as u64);
+    assign_stage.assign_locations(&mut pbf, blobs, &assign_stage_bar)?;
     assign_stage_bar.finish();
 
     debug!("Found positions for ways: {}", assign_stage);
 
-    debug!("Creating polygons");
-    let geometry = assign_stage.into_geometry();
-    debug!("Created {} polygons", geometry.len());
+    debug!("Creating regions");
+    let mut report = Report::default();
+    let regions = assign_stage.into_regions(&mut report);
+    debug!("Created {} regions", regions.len());
+
+    debug!("Validating regions");
+    let regions = validate_regions(regions, &mut report);
+    debug!("Kept {} valid regions", regions.len());
 
-    Ok(GeometryCollection::from_iter(geometry))
+    Ok((regions, report))
 }
@@ -0,0 +1,132 @@
+use core_geo::{tiled_union::TiledUnion, union::par_union};
+use geo::Geometry;
+use tracing::{debug, instrument};
+
+use crate::progress::progress_bar;
+use crate::region::Region;
+use crate::report::{Reason, Report};
+
+/// Union all overlapping regions together, so that the API doesn't need to at
+/// query time, handing each area to `on_area` along with whether a fallback was
+/// needed to make it, and returning how many there were. The OSM elements each
+/// area came from are lost.
+///
+/// With a `tile_size` (in degrees), this is done a tile at a time, and areas are
+/// handed over as soon as they're finished, which is needed for country-sized
+/// extracts. Regions whose union needed a fallback, so may not be exact, are added
+/// to `report`.
+#[instrument(skip(regions, report, on_area))]
+pub fn dissolve<F>(
+    regions: &[Region],
+    tile_size: Option<f64>,
+    report: &mut Report,
+    mut on_area: F,
+) -> Result<usize, Box<dyn std::error::Error>>
+where
+    F: FnMut(Geometry<f64>, bool) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let geometry: Vec<Geometry<f64>> = regions.iter().map(|r| r.geometry.clone()).collect();
+    debug!("dissolving {} regions", geometry.len());
+    let mut areas = 0;
+    let mut on_area = |geometry, degraded| {
+        areas += 1;
+        on_area(geometry, degraded)
+    };
+    let degraded = match tile_size {
+        Some(tile_size) => {
+            let tiled = TiledUnion::new(geometry, tile_size)?;
+            let bar = progress_bar(tiled.len() as u64);
+            let dissolved = tiled.union_each(
+                || bar.inc(1),
+                |area| on_area(Geometry::Polygon(area.polygon), area.degraded),
+            )?;
+            bar.finish();
+            dissolved.degraded
+        }
+        None => {
+            let dissolved = par_union(geometry)?;
+            let degraded: Vec<bool> = (0..dissolved.geometry.len())
+                .map(|i| dissolved.is_degraded(i))
+                .collect();
+            for (geometry, degraded) in dissolved.geometry.into_iter().zip(degraded) {
+                on_area(geometry, degraded)?;
+            }
+            dissolved.degraded
+        }
+    };
+    let mut degraded: Vec<&Region> = degraded
+        .iter()
+        .flat_map(|degraded| degraded.inputs.iter().map(|input| &regions[*input]))
+        .collect();
+    degraded.sort_by_key(|region| (region.osm_type, region.osm_id));
+    degraded.dedup_by_key(|region| (region.osm_type, region.osm_id));
+    for region in degraded {
+        report.add(Reason::UnionDegraded, region.osm_type, region.osm_id);
+    }
+    debug!("dissolved into {} polygons", areas);
+    Ok(areas)
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{polygon, Area, MultiPolygon};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::region::ElementKind;
+
+    fn region(osm_id: i64, geometry: Geometry<f64>) -> Region {
+        Region {
+            geometry,
+            osm_id,
+            osm_type: ElementKind::Way,
+            tag: "leisure=park".to_string(),
+        }
+    }
+
+    #[test]
+    fn overlapping_regions_are_merged() {
+        let regions = vec![
+            region(
+                1,
+                polygon![(x: 0., y: 0.), (x: 2., y: 0.), (x: 2., y: 2.), (x: 0., y: 2.)].into(),
+            ),
+            region(
+                2,
+                MultiPolygon::new(vec![
+                    polygon![(x: 1., y: 1.), (x: 3., y: 1.), (x: 3., y: 3.), (x: 1., y: 3.)],
+                    polygon![(x: 5., y: 5.), (x: 6., y: 5.), (x: 6., y: 6.), (x: 5., y: 6.)],
+                ])
+                .into(),
+            ),
+        ];
+        for tile_size in [None, Some(1.0)] {
+            let mut report = Report::default();
+            let mut dissolved = vec![];
+            dissolve(&regions, tile_size, &mut report, |geometry, degraded| {
+                assert!(!degraded);
+                dissolved.push(geometry);
+                Ok(())
+            })
+            .unwrap();
+            assert!(report.is_empty());
+            assert_eq!(dissolved.len(), 2);
+            assert_eq!(
+                dissolved.iter().map(|g| g.unsigned_area()).sum::<f64>(),
+                4.0 + 4.0 - 1.0 + 1.0
+            );
+        }
+    }
+
+    #[test]
+    fn nothing_to_dissolve() {
+        let mut dissolved = vec![];
+        let areas = dissolve(&[], None, &mut Report::default(), |geometry, _| {
+            dissolved.push(geometry);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(areas, 0);
+        assert_eq!(dissolved, vec![]);
+    }
+}
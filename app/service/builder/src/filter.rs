@@ -1,7 +1,112 @@
 use rustc_hash::FxHashSet as HashSet;
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, path::Path};
 
+/// A `key=value` tag, as written in a rules file
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "String")]
+pub struct Tag {
+    key: String,
+    value: String,
+}
+
+impl TryFrom<String> for Tag {
+    type Error = String;
+
+    fn try_from(tag: String) -> Result<Self, Self::Error> {
+        match tag.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !value.is_empty() => Ok(Tag {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(format!("expected tag of form 'key=value', got '{}'", tag)),
+        }
+    }
+}
+
+impl Tag {
+    fn as_pair(&self) -> (&str, &str) {
+        (&self.key, &self.value)
+    }
+}
+
+/// A tag which only counts as green if at least one of `requires_any` is also present
+#[derive(Deserialize, Debug)]
+pub struct ConditionalRule {
+    tag: Tag,
+    requires_any: Vec<Tag>,
+}
+
+/// A named set of rules deciding which tags count as green
+#[derive(Deserialize, Debug)]
+pub struct Profile {
+    #[serde(default)]
+    include: Vec<Tag>,
+    #[serde(default)]
+    exclude: Vec<Tag>,
+    #[serde(default)]
+    rules: Vec<ConditionalRule>,
+}
+
+/// Profiles loaded from a TOML or JSON rules file
+#[derive(Deserialize, Debug)]
+pub struct GreenTagRules {
+    profiles: BTreeMap<String, Profile>,
+}
+
+impl GreenTagRules {
+    /// Load rules from `path`; files ending in `.json` are parsed as JSON, anything else as TOML
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = fs::read_to_string(path)?;
+        let rules = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&content)?,
+            _ => toml::from_str(&content)?,
+        };
+        Ok(rules)
+    }
+
+    pub fn profile(&self, name: &str) -> Result<GreenTags, Box<dyn std::error::Error>> {
+        let profile = self.profiles.get(name).ok_or_else(|| {
+            format!(
+                "no profile named '{}', available: {:?}",
+                name,
+                self.profiles.keys().collect::<Vec<_>>()
+            )
+        })?;
+        Ok(GreenTags::from(profile))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+struct Conditional<'a> {
+    tag: (&'a str, &'a str),
+    requires_any: HashSet<(&'a str, &'a str)>,
+}
+
+#[derive(PartialEq, Debug)]
 pub struct GreenTags<'a> {
-    generic_tag_set: HashSet<(&'a str, &'a str)>,
+    /// in the order they were declared, which decides the tag reported when
+    /// several match
+    generic_tags: Vec<(&'a str, &'a str)>,
+    excluded_tag_set: HashSet<(&'a str, &'a str)>,
+    conditionals: Vec<Conditional<'a>>,
+}
+
+impl<'a> From<&'a Profile> for GreenTags<'a> {
+    fn from(profile: &'a Profile) -> Self {
+        Self {
+            generic_tags: profile.include.iter().map(Tag::as_pair).collect(),
+            excluded_tag_set: profile.exclude.iter().map(Tag::as_pair).collect(),
+            conditionals: profile
+                .rules
+                .iter()
+                .map(|rule| Conditional {
+                    tag: rule.tag.as_pair(),
+                    requires_any: rule.requires_any.iter().map(Tag::as_pair).collect(),
+                })
+                .collect(),
+        }
+    }
 }
 
 impl<'a> Default for GreenTags<'a> {
@@ -38,17 +143,138 @@ impl<'a> Default for GreenTags<'a> {
             ("landuse", "recreation_ground"),
             ("landuse", "village_green"),
         ];
-        let generic_tag_set: HashSet<(&str, &str)> = generic.into_iter().collect();
-        Self { generic_tag_set }
+        let garden = Conditional {
+            tag: ("leisure", "garden"),
+            requires_any: vec![("access", "yes"), ("garden:type", "community")]
+                .into_iter()
+                .collect(),
+        };
+        Self {
+            generic_tags: generic,
+            excluded_tag_set: HashSet::default(),
+            conditionals: vec![garden],
+        }
     }
 }
 
 impl<'a> GreenTags<'a> {
-    pub fn filter(&self, tag_set: HashSet<(&str, &str)>) -> bool {
-        if tag_set.contains(&("leisure", "garden")) {
-            tag_set.contains(&("access", "yes")) || tag_set.contains(&("garden:type", "community"))
-        } else {
-            tag_set.intersection(&self.generic_tag_set).count() > 0
+    /// Find the tag which marks `tag_set` as green, if any; when several do, the
+    /// first rule or included tag declared wins
+    pub fn matching_tag<'b>(
+        &self,
+        tag_set: &HashSet<(&'b str, &'b str)>,
+    ) -> Option<(&'b str, &'b str)> {
+        if tag_set
+            .iter()
+            .any(|tag| self.excluded_tag_set.contains(tag))
+        {
+            return None;
+        }
+        for conditional in self.conditionals.iter() {
+            if let Some(tag) = tag_set.iter().find(|tag| **tag == conditional.tag) {
+                let allowed = tag_set
+                    .iter()
+                    .any(|tag| conditional.requires_any.contains(tag));
+                return if allowed { Some(*tag) } else { None };
+            }
         }
+        self.generic_tags
+            .iter()
+            .find_map(|generic| tag_set.iter().find(|tag| **tag == *generic))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn tag_set<'b>(tags: &[(&'b str, &'b str)]) -> HashSet<(&'b str, &'b str)> {
+        tags.iter().copied().collect()
+    }
+
+    #[test]
+    fn bundled_default_profile_matches_built_in_default() {
+        let rules: GreenTagRules = toml::from_str(include_str!("../green_tags.toml")).unwrap();
+        let from_file = rules.profile("default").unwrap();
+        assert_eq!(from_file, GreenTags::default());
+    }
+
+    #[test]
+    fn garden_needs_access() {
+        let green_tags = GreenTags::default();
+        assert_eq!(
+            green_tags.matching_tag(&tag_set(&[("leisure", "garden")])),
+            None
+        );
+        assert_eq!(
+            green_tags.matching_tag(&tag_set(&[("leisure", "garden"), ("access", "yes")])),
+            Some(("leisure", "garden"))
+        );
+    }
+
+    #[test]
+    fn first_declared_tag_wins() {
+        let rules: GreenTagRules = toml::from_str(
+            r#"
+            [profiles.parks]
+            include = ["leisure=park", "landuse=grass", "natural=wood"]
+            "#,
+        )
+        .unwrap();
+        let green_tags = rules.profile("parks").unwrap();
+        // whatever order the set happens to iterate in
+        for tags in [
+            [("natural", "wood"), ("landuse", "grass")],
+            [("landuse", "grass"), ("natural", "wood")],
+        ] {
+            assert_eq!(
+                green_tags.matching_tag(&tag_set(&tags)),
+                Some(("landuse", "grass"))
+            );
+        }
+    }
+
+    #[test]
+    fn excluded_tags_win_over_included_tags() {
+        let rules: GreenTagRules = toml::from_str(
+            r#"
+            [profiles.parks]
+            include = ["leisure=park"]
+            exclude = ["access=private"]
+            "#,
+        )
+        .unwrap();
+        let green_tags = rules.profile("parks").unwrap();
+        assert_eq!(
+            green_tags.matching_tag(&tag_set(&[("leisure", "park")])),
+            Some(("leisure", "park"))
+        );
+        assert_eq!(
+            green_tags.matching_tag(&tag_set(&[("leisure", "park"), ("access", "private")])),
+            None
+        );
+    }
+
+    #[test]
+    fn rules_can_be_loaded_from_json() {
+        let rules: GreenTagRules =
+            serde_json::from_str(r#"{"profiles": {"woods": {"include": ["natural=wood"]}}}"#)
+                .unwrap();
+        assert!(rules.profile("woods").is_ok());
+        assert!(rules.profile("parks").is_err());
+    }
+
+    #[test]
+    fn malformed_tag_is_rejected() {
+        let rules = toml::from_str::<GreenTagRules>(
+            r#"
+            [profiles.broken]
+            include = ["leisure"]
+            "#,
+        );
+        assert!(rules.is_err());
     }
 }
@@ -1,3 +1,10 @@
 pub mod builder;
+pub mod dissolve;
 pub mod filter;
+pub mod nodes;
+pub mod pbf;
 pub mod progress;
+pub mod region;
+pub mod report;
+pub mod rings;
+pub mod validate;
@@ -0,0 +1,375 @@
+use std::{fs::File, io, path::Path};
+
+use geo::geometry::Coord;
+use memmap2::MmapMut;
+use rustc_hash::FxHashMap as HashMap;
+use tracing::debug;
+
+/// A Node position in decimicrodegrees (10⁻⁷), as stored in `.osm.pbf` files
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Location {
+    lon: i32,
+    lat: i32,
+}
+
+impl Location {
+    /// never a valid latitude, so used to mark empty slots in dense stores
+    const MISSING: Location = Location {
+        lon: 0,
+        lat: i32::MIN,
+    };
+
+    pub fn new(lon: i32, lat: i32) -> Self {
+        Location { lon, lat }
+    }
+
+    pub fn coord(&self) -> Coord<f64> {
+        Coord::from((self.lon as f64 / 1e7, self.lat as f64 / 1e7))
+    }
+
+    fn is_missing(&self) -> bool {
+        self.lat == Self::MISSING.lat
+    }
+
+    /// Packed so that all-zero bytes decode as `MISSING`, which lets freshly
+    /// allocated file space act as empty slots
+    fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[0..4].copy_from_slice(&self.lon.to_le_bytes());
+        bytes[4..8].copy_from_slice(&(self.lat ^ i32::MIN).to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let lon = i32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let lat = i32::from_le_bytes(bytes[4..8].try_into().unwrap()) ^ i32::MIN;
+        Location { lon, lat }
+    }
+}
+
+/// Somewhere to keep the positions of the Nodes referenced by wanted Ways
+pub trait NodeStore: Send + Sync {
+    /// Fails only for stores backed by files, such as when the disk is full
+    fn insert(&mut self, id: i64, location: Location) -> io::Result<()>;
+
+    /// Insert all of a block's locations at once
+    fn insert_all(&mut self, locations: &[(i64, Location)]) -> io::Result<()> {
+        for (id, location) in locations {
+            self.insert(*id, *location)?;
+        }
+        Ok(())
+    }
+
+    /// Called once after the last `insert` and before any `get`
+    fn finish(&mut self) {}
+
+    fn get(&self, id: i64) -> Option<Location>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Which `NodeStore` to use; this trades memory for speed. Only the Node positions
+/// are kept in the store: the ids of the Nodes referenced by wanted Ways are always
+/// kept in memory as well, at 8 bytes each plus 8 bytes per reference.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NodeStoreKind {
+    /// hash map in memory; fast, but around 40 bytes per stored Node
+    #[default]
+    Hash,
+    /// array in memory indexed by Node id; 8 bytes per id up to the largest id
+    /// stored, so best for small extracts with low ids
+    Dense,
+    /// sorted array in memory; 16 bytes per stored Node
+    Sorted,
+    /// array indexed by Node id in a sparse temporary file, which the OS pages
+    /// in and out, so the positions don't need to fit in memory
+    Mmap,
+}
+
+impl NodeStoreKind {
+    pub const ALL: [NodeStoreKind; 4] = [
+        NodeStoreKind::Hash,
+        NodeStoreKind::Dense,
+        NodeStoreKind::Sorted,
+        NodeStoreKind::Mmap,
+    ];
+
+    /// Create an empty store; `dir` is where the `Mmap` store keeps its file, and
+    /// defaults to the system temporary directory
+    pub fn create(
+        &self,
+        dir: Option<&Path>,
+    ) -> Result<Box<dyn NodeStore>, Box<dyn std::error::Error>> {
+        Ok(match self {
+            NodeStoreKind::Hash => Box::<HashNodeStore>::default(),
+            NodeStoreKind::Dense => Box::<DenseNodeStore>::default(),
+            NodeStoreKind::Sorted => Box::<SortedNodeStore>::default(),
+            NodeStoreKind::Mmap => {
+                let dir = dir.map_or_else(std::env::temp_dir, Path::to_path_buf);
+                Box::new(MmapNodeStore::new(&dir)?)
+            }
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct HashNodeStore {
+    locations: HashMap<i64, Location>,
+}
+
+impl NodeStore for HashNodeStore {
+    fn insert(&mut self, id: i64, location: Location) -> io::Result<()> {
+        self.locations.insert(id, location);
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<Location> {
+        self.locations.get(&id).copied()
+    }
+
+    fn len(&self) -> usize {
+        self.locations.len()
+    }
+}
+
+/// Dense stores only index non-negative ids; anything else (such as the negative
+/// ids used for new objects by editors) is kept here
+#[derive(Default)]
+struct Overflow {
+    locations: HashMap<i64, Location>,
+}
+
+impl Overflow {
+    fn index(&mut self, id: i64, location: Location) -> Option<usize> {
+        match usize::try_from(id) {
+            Ok(index) => Some(index),
+            Err(_) => {
+                self.locations.insert(id, location);
+                None
+            }
+        }
+    }
+
+    fn get(&self, id: i64) -> Result<usize, Option<Location>> {
+        usize::try_from(id).map_err(|_| self.locations.get(&id).copied())
+    }
+}
+
+#[derive(Default)]
+pub struct DenseNodeStore {
+    locations: Vec<Location>,
+    overflow: Overflow,
+    count: usize,
+}
+
+impl NodeStore for DenseNodeStore {
+    fn insert(&mut self, id: i64, location: Location) -> io::Result<()> {
+        if let Some(index) = self.overflow.index(id, location) {
+            if index >= self.locations.len() {
+                self.locations.resize(index + 1, Location::MISSING);
+            }
+            if self.locations[index].is_missing() {
+                self.count += 1;
+            }
+            self.locations[index] = location;
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<Location> {
+        match self.overflow.get(id) {
+            Ok(index) => self
+                .locations
+                .get(index)
+                .filter(|location| !location.is_missing())
+                .copied(),
+            Err(location) => location,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.count + self.overflow.locations.len()
+    }
+}
+
+#[derive(Default)]
+pub struct SortedNodeStore {
+    locations: Vec<(i64, Location)>,
+}
+
+impl NodeStore for SortedNodeStore {
+    fn insert(&mut self, id: i64, location: Location) -> io::Result<()> {
+        self.locations.push((id, location));
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        self.locations.sort_unstable_by_key(|(id, _)| *id);
+        self.locations.dedup_by_key(|(id, _)| *id);
+        self.locations.shrink_to_fit();
+    }
+
+    fn get(&self, id: i64) -> Option<Location> {
+        self.locations
+            .binary_search_by_key(&id, |(id, _)| *id)
+            .ok()
+            .map(|index| self.locations[index].1)
+    }
+
+    fn len(&self) -> usize {
+        self.locations.len()
+    }
+}
+
+pub struct MmapNodeStore {
+    file: File,
+    map: MmapMut,
+    overflow: Overflow,
+    count: usize,
+}
+
+impl MmapNodeStore {
+    const SLOT: usize = 8;
+    /// the file grows in steps of this many slots
+    const GROWTH: usize = 1 << 20;
+
+    pub fn new(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = tempfile::tempfile_in(dir)?;
+        file.set_len((Self::GROWTH * Self::SLOT) as u64)?;
+        // SAFETY: the file is anonymous and only this store has a handle to it, so
+        // nothing else can truncate or write to it while it's mapped
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(MmapNodeStore {
+            file,
+            map,
+            overflow: Overflow::default(),
+            count: 0,
+        })
+    }
+
+    fn slots(&self) -> usize {
+        self.map.len() / Self::SLOT
+    }
+
+    /// Grow the (sparse) file so that `index` fits, at least doubling it so that
+    /// remapping is rare
+    fn grow_to(&mut self, index: usize) -> io::Result<()> {
+        let slots = (index / Self::GROWTH + 1).max(2 * self.slots() / Self::GROWTH) * Self::GROWTH;
+        debug!("growing node store to {} slots", slots);
+        self.file.set_len((slots * Self::SLOT) as u64)?;
+        // SAFETY: as in `new`, only this store has a handle to the file, and it has
+        // just been grown, never shrunk, so the old map stays valid until replaced
+        self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        Ok(())
+    }
+
+    fn slot(&self, index: usize) -> Location {
+        Location::from_bytes(&self.map[index * Self::SLOT..(index + 1) * Self::SLOT])
+    }
+}
+
+impl NodeStore for MmapNodeStore {
+    fn insert(&mut self, id: i64, location: Location) -> io::Result<()> {
+        if let Some(index) = self.overflow.index(id, location) {
+            if index >= self.slots() {
+                self.grow_to(index)?;
+            }
+            if self.slot(index).is_missing() {
+                self.count += 1;
+            }
+            self.map[index * Self::SLOT..(index + 1) * Self::SLOT]
+                .copy_from_slice(&location.to_bytes());
+        }
+        Ok(())
+    }
+
+    /// Grows the file at most once for the whole block
+    fn insert_all(&mut self, locations: &[(i64, Location)]) -> io::Result<()> {
+        let largest = locations.iter().map(|(id, _)| *id).max();
+        if let Some(index) = largest.and_then(|id| usize::try_from(id).ok()) {
+            if index >= self.slots() {
+                self.grow_to(index)?;
+            }
+        }
+        for (id, location) in locations {
+            self.insert(*id, *location)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<Location> {
+        match self.overflow.get(id) {
+            Ok(index) if index < self.slots() => {
+                Some(self.slot(index)).filter(|location| !location.is_missing())
+            }
+            Ok(_) => None,
+            Err(location) => location,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.count + self.overflow.locations.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn check_store(kind: NodeStoreKind) {
+        let dir = std::env::temp_dir();
+        let mut store = kind.create(Some(&dir)).unwrap();
+        store.insert(3, Location::new(0, 0)).unwrap();
+        store
+            .insert(1, Location::new(-1_800_000_000, 900_000_000))
+            .unwrap();
+        store
+            .insert_all(&[
+                (-7, Location::new(12, -34)),
+                (3_000_000, Location::new(5, 6)),
+            ])
+            .unwrap();
+        store.insert(3, Location::new(0, 0)).unwrap();
+        store.finish();
+
+        assert_eq!(store.len(), 4, "{:?}", kind);
+        assert_eq!(store.get(3), Some(Location::new(0, 0)), "{:?}", kind);
+        assert_eq!(
+            store.get(1),
+            Some(Location::new(-1_800_000_000, 900_000_000)),
+            "{:?}",
+            kind
+        );
+        assert_eq!(store.get(-7), Some(Location::new(12, -34)), "{:?}", kind);
+        assert_eq!(
+            store.get(3_000_000),
+            Some(Location::new(5, 6)),
+            "{:?}",
+            kind
+        );
+        assert_eq!(store.get(2), None, "{:?}", kind);
+        assert_eq!(store.get(-1), None, "{:?}", kind);
+        assert_eq!(store.get(i64::MAX), None, "{:?}", kind);
+    }
+
+    #[test]
+    fn all_stores_keep_locations() {
+        for kind in NodeStoreKind::ALL {
+            check_store(kind);
+        }
+    }
+
+    #[test]
+    fn location_converts_to_degrees() {
+        assert_eq!(
+            Location::new(-31_000_000, 559_000_000).coord(),
+            Coord::from((-3.1, 55.9))
+        );
+    }
+}
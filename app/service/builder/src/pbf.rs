@@ -0,0 +1,143 @@
+use std::{fs::File, io::BufReader, ops::RangeInclusive, path::Path};
+
+use osmpbf::{BlobReader, BlobType, ByteOffset, PrimitiveBlock};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use tracing::{debug, instrument};
+
+/// The smallest and largest ids of the Nodes and Ways found in a blob
+#[derive(Debug, Default, Clone)]
+pub struct IdRanges {
+    pub nodes: Option<RangeInclusive<i64>>,
+    pub ways: Option<RangeInclusive<i64>>,
+}
+
+impl IdRanges {
+    fn of(block: &PrimitiveBlock) -> Self {
+        fn extend(range: &mut Option<RangeInclusive<i64>>, id: i64) {
+            *range = Some(match range.take() {
+                Some(r) => (*r.start()).min(id)..=(*r.end()).max(id),
+                None => id..=id,
+            });
+        }
+
+        let mut ranges = IdRanges::default();
+        for group in block.groups() {
+            for node in group.nodes() {
+                extend(&mut ranges.nodes, node.id());
+            }
+            for node in group.dense_nodes() {
+                extend(&mut ranges.nodes, node.id());
+            }
+            for way in group.ways() {
+                extend(&mut ranges.ways, way.id());
+            }
+        }
+        ranges
+    }
+}
+
+struct BlobInfo {
+    offset: ByteOffset,
+    ranges: Option<IdRanges>,
+}
+
+/// A `.osm.pbf` file which remembers which ids each of its blobs contains, so that
+/// later passes only need to decode the blobs which could hold the elements wanted.
+///
+/// Building the index only reads blob headers; the id ranges of a blob are filled
+/// in the first time it is decoded.
+pub struct IndexedPbf {
+    reader: BlobReader<BufReader<File>>,
+    blobs: Vec<BlobInfo>,
+}
+
+impl IndexedPbf {
+    #[instrument]
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut reader = BlobReader::seekable_from_path(path)?;
+        let mut blobs = vec![];
+        while let Some(result) = reader.next_header_skip_blob() {
+            let (header, offset) = result?;
+            if let BlobType::OsmData = header.blob_type() {
+                blobs.push(BlobInfo {
+                    offset: offset.ok_or("seekable reader should give blob offsets")?,
+                    ranges: None,
+                });
+            }
+        }
+        debug!("indexed {} data blobs", blobs.len());
+        Ok(IndexedPbf { reader, blobs })
+    }
+
+    /// Indexes of the blobs which might contain something wanted by `predicate`;
+    /// this includes any blob which hasn't been read yet
+    pub fn select<P>(&self, predicate: P) -> Vec<usize>
+    where
+        P: Fn(&IdRanges) -> bool,
+    {
+        self.blobs
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| info.ranges.as_ref().map_or(true, &predicate))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Decode `blobs` in parallel, turning each into a partial result with `map_op` and
+    /// combining those with `reduce_op`, like `osmpbf::ElementReader::par_map_reduce`.
+    /// Blobs are still read from the file one at a time, but decompressed and decoded
+    /// on all cores.
+    pub fn par_map_reduce<MP, RD, ID, T>(
+        &mut self,
+        blobs: Vec<usize>,
+        map_op: MP,
+        identity: ID,
+        reduce_op: RD,
+    ) -> Result<T, Box<dyn std::error::Error>>
+    where
+        MP: Fn(&PrimitiveBlock) -> T + Sync + Send,
+        RD: Fn(T, T) -> T + Sync + Send,
+        ID: Fn() -> T + Sync + Send,
+        T: Send,
+    {
+        let reader = &mut self.reader;
+        let infos = &self.blobs;
+        let (result, ranges) = blobs
+            .into_iter()
+            .map(|i| {
+                reader
+                    .blob_from_offset(infos[i].offset)
+                    .map(|blob| (i, blob))
+            })
+            .par_bridge()
+            .map(|read| {
+                let (i, blob) = read?;
+                let block = blob.to_primitiveblock()?;
+                let ranges = match infos[i].ranges {
+                    Some(_) => vec![],
+                    None => vec![(i, IdRanges::of(&block))],
+                };
+                Ok((map_op(&block), ranges))
+            })
+            .try_reduce(
+                || (identity(), vec![]),
+                |(a, mut a_ranges), (b, b_ranges)| {
+                    a_ranges.extend(b_ranges);
+                    Ok((reduce_op(a, b), a_ranges))
+                },
+            )
+            .map_err(|e: osmpbf::Error| e)?;
+        for (i, blob_ranges) in ranges {
+            self.blobs[i].ranges = Some(blob_ranges);
+        }
+        Ok(result)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blobs.is_empty()
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt::{Display, Formatter};
+
+use core_geo::DEGRADED_PROPERTY;
+use geo::geometry::Geometry;
+use geozero::{ColumnValue, FeatureProcessor, GeozeroGeometry, PropertyProcessor};
+
+/// The kind of OSM element a region was derived from
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum ElementKind {
+    Way,
+    Relation,
+}
+
+impl Display for ElementKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ElementKind::Way => write!(f, "way"),
+            ElementKind::Relation => write!(f, "relation"),
+        }
+    }
+}
+
+/// A green area, along with the OSM element it came from
+#[derive(Debug)]
+pub struct Region {
+    pub geometry: Geometry<f64>,
+    pub osm_id: i64,
+    pub osm_type: ElementKind,
+    /// the green tag which matched, as `key=value`
+    pub tag: String,
+}
+
+impl Region {
+    fn process_properties<P: PropertyProcessor>(
+        &self,
+        processor: &mut P,
+    ) -> geozero::error::Result<()> {
+        processor.property(0, "osm_id", &ColumnValue::Long(self.osm_id))?;
+        processor.property(
+            1,
+            "osm_type",
+            &ColumnValue::String(&self.osm_type.to_string()),
+        )?;
+        processor.property(2, "tag", &ColumnValue::String(&self.tag))?;
+        Ok(())
+    }
+}
+
+/// Write all regions, including their properties, as features to `processor`
+pub fn process_regions<P: FeatureProcessor>(
+    regions: &[Region],
+    processor: &mut P,
+) -> geozero::error::Result<()> {
+    processor.dataset_begin(None)?;
+    for (idx, region) in regions.iter().enumerate() {
+        let idx = idx as u64;
+        processor.feature_begin(idx)?;
+        processor.properties_begin()?;
+        region.process_properties(processor)?;
+        processor.properties_end()?;
+        processor.geometry_begin()?;
+        region.geometry.process_geom(processor)?;
+        processor.geometry_end()?;
+        processor.feature_end(idx)?;
+    }
+    processor.dataset_end()
+}
+
+/// Writes dissolved areas as features to a processor one at a time, for when
+/// there are too many to hold at once. Each has only the `DEGRADED_PROPERTY`.
+pub struct AreaWriter<'a, P: FeatureProcessor> {
+    processor: &'a mut P,
+    written: u64,
+}
+
+impl<'a, P: FeatureProcessor> AreaWriter<'a, P> {
+    pub fn begin(processor: &'a mut P) -> geozero::error::Result<Self> {
+        processor.dataset_begin(None)?;
+        Ok(AreaWriter {
+            processor,
+            written: 0,
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        geometry: &Geometry<f64>,
+        degraded: bool,
+    ) -> geozero::error::Result<()> {
+        self.processor.feature_begin(self.written)?;
+        self.processor.properties_begin()?;
+        self.processor
+            .property(0, DEGRADED_PROPERTY, &ColumnValue::Bool(degraded))?;
+        self.processor.properties_end()?;
+        self.processor.geometry_begin()?;
+        geometry.process_geom(self.processor)?;
+        self.processor.geometry_end()?;
+        self.processor.feature_end(self.written)?;
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> geozero::error::Result<()> {
+        self.processor.dataset_end()
+    }
+}
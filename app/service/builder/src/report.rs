@@ -0,0 +1,111 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+};
+
+use crate::region::ElementKind;
+
+/// Why an element was repaired or left out while building
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Reason {
+    /// a ring referenced Nodes which aren't in the file, and was kept without them
+    MissingNodesDropped,
+    /// a ring referenced Nodes which aren't in the file, and too few were left to
+    /// close it, so it was left out
+    MissingNodesSkipped,
+    /// a Relation had member Ways which aren't in the file
+    MissingMemberWays,
+    /// a Relation's member Ways didn't join up into a closed ring, so those Ways
+    /// were left out
+    UnclosedRingDropped,
+    /// a Relation had no outer ring which could be closed, so it was left out
+    NoClosedOuterRing,
+    /// a Way wasn't closed, so was closed by joining its ends
+    UnclosedRingClosed,
+    /// a ring had fewer than 4 coords or no area, so was left out
+    DegenerateRingDropped,
+    /// a ring crossed or touched itself, so was split into simple rings
+    SelfIntersectionFixed,
+    /// a hole wasn't inside any outer ring, so was left out
+    StrayHoleDropped,
+    /// holes crossed their outer ring or each other, so were cut out of it instead
+    CrossingRingsFixed,
+    /// a polygon couldn't be repaired, so was left out
+    InvalidDropped,
+    /// unioning a polygon with the areas it overlaps needed a fallback, so the
+    /// dissolved area may not be exact
+    UnionDegraded,
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            Reason::MissingNodesDropped => "missing nodes dropped",
+            Reason::MissingNodesSkipped => "skipped for missing nodes",
+            Reason::MissingMemberWays => "missing member ways",
+            Reason::UnclosedRingDropped => "unclosed member ways dropped",
+            Reason::NoClosedOuterRing => "skipped with no closed outer ring",
+            Reason::UnclosedRingClosed => "unclosed rings closed",
+            Reason::DegenerateRingDropped => "degenerate rings dropped",
+            Reason::SelfIntersectionFixed => "self-intersections fixed",
+            Reason::StrayHoleDropped => "holes outside outer rings dropped",
+            Reason::CrossingRingsFixed => "crossing rings fixed",
+            Reason::InvalidDropped => "skipped as invalid",
+            Reason::UnionDegraded => "inexactly dissolved",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// The elements which were repaired or skipped, by reason
+#[derive(Default, Debug)]
+pub struct Report {
+    elements: BTreeMap<Reason, Vec<(ElementKind, i64)>>,
+}
+
+impl Report {
+    /// how many ids to list for each reason when displayed
+    const SHOWN: usize = 10;
+
+    pub fn add(&mut self, reason: Reason, kind: ElementKind, id: i64) {
+        self.elements.entry(reason).or_default().push((kind, id));
+    }
+
+    pub fn merge(&mut self, other: Report) {
+        for (reason, elements) in other.elements {
+            self.elements.entry(reason).or_default().extend(elements);
+        }
+    }
+
+    pub fn elements(&self, reason: Reason) -> &[(ElementKind, i64)] {
+        self.elements.get(&reason).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.elements.is_empty() {
+            return write!(f, "nothing repaired or skipped");
+        }
+        for (reason, elements) in self.elements.iter() {
+            let mut elements = elements.clone();
+            elements.sort();
+            write!(f, "\n  {}: {}", reason, elements.len())?;
+            let shown: Vec<String> = elements
+                .iter()
+                .take(Self::SHOWN)
+                .map(|(kind, id)| format!("{}/{}", kind, id))
+                .collect();
+            write!(f, " [{}", shown.join(", "))?;
+            if elements.len() > Self::SHOWN {
+                write!(f, ", ...")?;
+            }
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+}
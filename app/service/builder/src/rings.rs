@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use geo::{Contains, InteriorPoint, LineString, MultiPolygon, Polygon};
+use tracing::trace;
+
+/// Stitch together the member Ways of a Relation (given as lists of node ids) into
+/// closed rings, by joining Ways which share an end node, reversing them where needed.
+///
+/// Ways which are already closed are used as-is. Any chain of Ways which cannot
+/// be closed is dropped, and how many chains were is returned alongside the rings.
+pub fn assemble_rings<T>(segments: Vec<Vec<T>>) -> (Vec<Vec<T>>, usize)
+where
+    T: Copy + Eq + Hash + std::fmt::Debug,
+{
+    let mut rings: Vec<Vec<T>> = vec![];
+    let mut dropped = 0;
+    // open segments are taken out as they're joined on, and found by their end
+    // nodes, so that large Relations don't need a scan for every join
+    let mut open: Vec<Option<Vec<T>>> = vec![];
+    let mut by_end: HashMap<T, Vec<usize>> = HashMap::new();
+    for segment in segments.into_iter() {
+        if segment.len() < 2 {
+            continue;
+        }
+        if is_closed(&segment) {
+            rings.push(segment);
+        } else {
+            for end in [segment[0], segment[segment.len() - 1]] {
+                by_end.entry(end).or_default().push(open.len());
+            }
+            open.push(Some(segment));
+        }
+    }
+
+    for start in (0..open.len()).rev() {
+        let Some(mut ring) = open[start].take() else {
+            continue;
+        };
+        // once nothing joins on to the end, the chain is turned round to try its
+        // other end, so that all of an unclosable chain is dropped at once
+        let mut turned = false;
+        loop {
+            if is_closed(&ring) {
+                rings.push(ring);
+                break;
+            }
+            let end = *ring.last().unwrap();
+            let next = by_end.get_mut(&end).and_then(|candidates| {
+                std::iter::from_fn(|| candidates.pop()).find_map(|i| open[i].take())
+            });
+            match next {
+                Some(mut segment) => {
+                    if *segment.first().unwrap() != end {
+                        segment.reverse();
+                    }
+                    ring.extend(segment.into_iter().skip(1));
+                }
+                None if !turned => {
+                    ring.reverse();
+                    turned = true;
+                }
+                None => {
+                    trace!(
+                        "could not close ring, from {:?} to {:?}",
+                        ring.first(),
+                        ring.last()
+                    );
+                    dropped += 1;
+                    break;
+                }
+            }
+        }
+    }
+
+    (rings, dropped)
+}
+
+/// Build polygons from assembled outer and inner rings, attaching each inner ring as
+/// a hole of the outer ring which contains it. Inner rings which are not inside any
+/// outer ring are dropped.
+pub fn polygons_from_rings(
+    outer: Vec<LineString<f64>>,
+    inner: Vec<LineString<f64>>,
+) -> MultiPolygon<f64> {
+    let mut polygons: Vec<Polygon<f64>> = outer
+        .into_iter()
+        .map(|ring| Polygon::new(ring, vec![]))
+        .collect();
+    for ring in inner.into_iter() {
+        let Some(point) = Polygon::new(ring.clone(), vec![]).interior_point() else {
+            continue;
+        };
+        match polygons.iter_mut().find(|p| p.contains(&point)) {
+            Some(polygon) => polygon.interiors_push(ring),
+            None => trace!("inner ring is not within any outer ring, dropping"),
+        }
+    }
+    MultiPolygon::new(polygons)
+}
+
+fn is_closed<T: Eq>(segment: &[T]) -> bool {
+    segment.len() > 1 && segment.first() == segment.last()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn square(x: f64, y: f64, size: f64) -> LineString<f64> {
+        vec![
+            (x, y),
+            (x + size, y),
+            (x + size, y + size),
+            (x, y + size),
+            (x, y),
+        ]
+        .into()
+    }
+
+    #[test]
+    fn closed_way_is_kept_as_is() {
+        let (rings, dropped) = assemble_rings(vec![vec![1, 2, 3, 1]]);
+        assert_eq!(rings, vec![vec![1, 2, 3, 1]]);
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn ways_joined_end_to_end() {
+        let (rings, _) = assemble_rings(vec![vec![1, 2, 3], vec![3, 4, 1]]);
+        assert_eq!(rings, vec![vec![3, 4, 1, 2, 3]]);
+    }
+
+    #[test]
+    fn ways_joined_when_pointing_in_opposite_directions() {
+        let (rings, _) = assemble_rings(vec![vec![1, 2, 3], vec![5, 4, 3], vec![5, 6, 1]]);
+        assert_eq!(rings.len(), 1);
+        let mut nodes = rings[0].clone();
+        assert_eq!(nodes.first(), nodes.last());
+        nodes.pop();
+        nodes.sort();
+        assert_eq!(nodes, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn many_ways_join_into_one_ring() {
+        // a ring of 10,000 two-node Ways, every other one reversed, out of order
+        let n = 10_000;
+        let mut segments: Vec<Vec<usize>> = (0..n)
+            .map(|i| match i % 2 {
+                0 => vec![i, (i + 1) % n],
+                _ => vec![(i + 1) % n, i],
+            })
+            .collect();
+        segments.sort_by_key(|s| (s[0] * 7919) % n);
+        let (rings, _) = assemble_rings(segments);
+        assert_eq!(rings.len(), 1);
+        assert_eq!(rings[0].len(), n + 1);
+    }
+
+    #[test]
+    fn multiple_separate_rings() {
+        let (rings, _) = assemble_rings(vec![vec![1, 2, 3], vec![10, 11, 12, 10], vec![3, 4, 1]]);
+        assert_eq!(rings.len(), 2);
+        assert!(rings.contains(&vec![10, 11, 12, 10]));
+    }
+
+    #[test]
+    fn unclosable_ways_are_dropped() {
+        let (rings, dropped) =
+            assemble_rings(vec![vec![1, 2, 3], vec![3, 4, 5], vec![10, 11, 12, 10]]);
+        assert_eq!(rings, vec![vec![10, 11, 12, 10]]);
+        assert_eq!(dropped, 1);
+    }
+
+    #[test]
+    fn inner_ring_attached_to_containing_outer_ring() {
+        let outer = vec![square(0.0, 0.0, 1.0), square(10.0, 10.0, 5.0)];
+        let inner = vec![square(11.0, 11.0, 1.0)];
+        let multi_polygon = polygons_from_rings(outer, inner);
+        assert_eq!(multi_polygon.0.len(), 2);
+        assert!(multi_polygon.0[0].interiors().is_empty());
+        assert_eq!(multi_polygon.0[1].interiors(), &[square(11.0, 11.0, 1.0)]);
+    }
+
+    #[test]
+    fn inner_ring_outside_all_outer_rings_is_dropped() {
+        let outer = vec![square(0.0, 0.0, 1.0)];
+        let inner = vec![square(5.0, 5.0, 1.0)];
+        let multi_polygon = polygons_from_rings(outer, inner);
+        assert_eq!(multi_polygon.0.len(), 1);
+        assert!(multi_polygon.0[0].interiors().is_empty());
+    }
+}
@@ -0,0 +1,406 @@
+use std::{collections::BTreeSet, panic};
+
+use geo::{
+    kernels::RobustKernel,
+    sweep::{Cross, Intersections, LineOrPoint},
+    Area, BooleanOps, BoundingRect, Contains, Coord, Geometry, InteriorPoint, Intersects, Kernel,
+    Line, LineIntersection, LineString, MultiPolygon, Orientation, Polygon, Relate,
+};
+use rayon::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+use tracing::{debug, trace};
+
+use crate::region::{ElementKind, Region};
+use crate::report::{Reason, Report};
+
+/// Check and repair the geometry of every region, so that only valid polygons are
+/// written; regions which are left with nothing are dropped. What was fixed or
+/// dropped is added to `report`.
+pub fn validate_regions(regions: Vec<Region>, report: &mut Report) -> Vec<Region> {
+    let validated: Vec<(Option<Region>, BTreeSet<Reason>, _)> = regions
+        .into_par_iter()
+        .map(|region| {
+            let element = (region.osm_type, region.osm_id);
+            let mut issues = BTreeSet::new();
+            let region = repair_region(region, &mut issues);
+            (region, issues, element)
+        })
+        .collect();
+
+    let mut valid = vec![];
+    for (region, issues, (kind, id)) in validated {
+        for reason in issues {
+            report.add(reason, kind, id);
+        }
+        valid.extend(region);
+    }
+    debug!("{} regions valid", valid.len());
+    valid
+}
+
+fn repair_region(region: Region, issues: &mut BTreeSet<Reason>) -> Option<Region> {
+    let polygons: Vec<Polygon<f64>> = match region.geometry {
+        Geometry::Polygon(polygon) => repair_polygon(polygon, issues),
+        Geometry::MultiPolygon(multi_polygon) => multi_polygon
+            .into_iter()
+            .flat_map(|polygon| repair_polygon(polygon, issues))
+            .collect(),
+        other => {
+            trace!("not an area: {:?}", other);
+            vec![]
+        }
+    };
+    let geometry = match (region.osm_type, polygons.len()) {
+        (_, 0) => return None,
+        (ElementKind::Way, 1) => Geometry::Polygon(polygons.into_iter().next().unwrap()),
+        _ => Geometry::MultiPolygon(MultiPolygon::new(polygons)),
+    };
+    Some(Region { geometry, ..region })
+}
+
+/// Repair a single polygon, which may split it into several
+fn repair_polygon(polygon: Polygon<f64>, issues: &mut BTreeSet<Reason>) -> Vec<Polygon<f64>> {
+    let (exterior, interiors) = polygon.into_inner();
+    let (mut outers, mut holes) = repair_ring(exterior, issues);
+    for interior in interiors {
+        // whatever is inside a hole is outside the polygon, and the other way round
+        let (hole_outers, hole_inners) = repair_ring(interior, issues);
+        holes.extend(hole_outers);
+        outers.extend(hole_inners);
+    }
+    if outers.is_empty() {
+        return vec![];
+    }
+
+    // each hole goes in the outer ring containing it, or else the first it crosses
+    let exteriors: Vec<Polygon<f64>> = outers
+        .iter()
+        .map(|ring| Polygon::new(ring.clone(), vec![]))
+        .collect();
+    let mut polygons = exteriors.clone();
+    for hole in holes {
+        let point = Polygon::new(hole.clone(), vec![]).interior_point();
+        let within = point.and_then(|point| exteriors.iter().position(|e| e.contains(&point)));
+        match within.or_else(|| exteriors.iter().position(|e| e.intersects(&hole))) {
+            Some(i) => polygons[i].interiors_push(hole),
+            None => {
+                issues.insert(Reason::StrayHoleDropped);
+            }
+        }
+    }
+
+    polygons
+        .into_iter()
+        .filter_map(|polygon| {
+            if holes_are_valid(&polygon) {
+                return Some(vec![polygon]);
+            }
+            match cut_holes(&polygon) {
+                Some(repaired) => {
+                    issues.insert(Reason::CrossingRingsFixed);
+                    Some(repaired.0)
+                }
+                None => {
+                    issues.insert(Reason::InvalidDropped);
+                    None
+                }
+            }
+        })
+        .flatten()
+        .collect()
+}
+
+/// Whether every hole of `polygon` is within its exterior, and no two holes overlap
+fn holes_are_valid(polygon: &Polygon<f64>) -> bool {
+    let exterior = Polygon::new(polygon.exterior().clone(), vec![]);
+    if !polygon
+        .interiors()
+        .iter()
+        .all(|hole| exterior.contains(hole))
+    {
+        return false;
+    }
+    let holes: Vec<Polygon<f64>> = polygon
+        .interiors()
+        .iter()
+        .map(|hole| Polygon::new(hole.clone(), vec![]))
+        .collect();
+    let bounds: Vec<_> = holes.iter().map(|hole| hole.bounding_rect()).collect();
+    for i in 0..holes.len() {
+        for j in (i + 1)..holes.len() {
+            let (Some(a), Some(b)) = (bounds[i], bounds[j]) else {
+                continue;
+            };
+            // holes may touch, but not share any area
+            if a.intersects(&b) {
+                let matrix = holes[i].relate(&holes[j]);
+                if matrix.is_intersects() && !matrix.is_touches() {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Rebuild `polygon` by cutting all of its holes out of its exterior; `None` if
+/// the boolean operations give up on it
+fn cut_holes(polygon: &Polygon<f64>) -> Option<MultiPolygon<f64>> {
+    let exterior = MultiPolygon::new(vec![Polygon::new(polygon.exterior().clone(), vec![])]);
+    let holes = polygon.interiors().to_vec();
+    panic::catch_unwind(|| {
+        let holes = holes
+            .into_iter()
+            .fold(MultiPolygon::new(vec![]), |union, hole| {
+                union.union(&MultiPolygon::new(vec![Polygon::new(hole, vec![])]))
+            });
+        exterior.difference(&holes)
+    })
+    .ok()
+}
+
+/// Repair a single ring, giving the rings it encloses along with any rings found
+/// inside those (such as when a ring winds round twice)
+fn repair_ring(
+    ring: LineString<f64>,
+    issues: &mut BTreeSet<Reason>,
+) -> (Vec<LineString<f64>>, Vec<LineString<f64>>) {
+    // rings in a `Polygon` are always closed, but may repeat coords
+    let mut coords = ring.0;
+    coords.dedup();
+    let ring = LineString::new(coords);
+    if ring.0.len() < 4 || is_flat(&ring) {
+        issues.insert(Reason::DegenerateRingDropped);
+        return (vec![], vec![]);
+    }
+
+    let crossings = self_intersections(&ring);
+    let loops = if crossings.is_empty() {
+        vec![ring].into_iter().filter(has_area).collect()
+    } else {
+        issues.insert(Reason::SelfIntersectionFixed);
+        split_loops(node_ring(&ring, crossings))
+    };
+    if loops.is_empty() {
+        issues.insert(Reason::DegenerateRingDropped);
+        return (vec![], vec![]);
+    }
+    nest_loops(loops)
+}
+
+/// Whether all the coords of `ring` lie on one line
+fn is_flat(ring: &LineString<f64>) -> bool {
+    let first = ring.0[0];
+    let Some(second) = ring.0.iter().find(|coord| **coord != first) else {
+        return true;
+    };
+    ring.0
+        .iter()
+        .all(|coord| RobustKernel::orient2d(first, *second, *coord) == Orientation::Collinear)
+}
+
+fn has_area(ring: &LineString<f64>) -> bool {
+    ring.0.len() >= 4 && Polygon::new(ring.clone(), vec![]).unsigned_area() > 0.0
+}
+
+/// A segment of a ring, remembering where it is in the ring
+#[derive(Clone, Debug)]
+struct Segment {
+    index: usize,
+    line: Line<f64>,
+}
+
+impl Cross for Segment {
+    type Scalar = f64;
+
+    fn line(&self) -> LineOrPoint<Self::Scalar> {
+        self.line.into()
+    }
+}
+
+/// Where `ring` crosses or touches itself, given as the index of the segment and
+/// the point on it; segments meeting end to end aren't counted
+fn self_intersections(ring: &LineString<f64>) -> Vec<(usize, Coord<f64>)> {
+    let segments = ring.lines().count();
+    let adjacent = |i: usize, j: usize| i.abs_diff(j) == 1 || i.abs_diff(j) == segments - 1;
+    let mut crossings = vec![];
+    let lines = ring
+        .lines()
+        .enumerate()
+        .map(|(index, line)| Segment { index, line });
+    for (a, b, intersection) in Intersections::from_iter(lines) {
+        match intersection {
+            LineIntersection::SinglePoint { intersection, .. } => {
+                let shared_end = [a.line.start, a.line.end].contains(&intersection)
+                    && [b.line.start, b.line.end].contains(&intersection);
+                if !(adjacent(a.index, b.index) && shared_end) {
+                    crossings.push((a.index, intersection));
+                    crossings.push((b.index, intersection));
+                }
+            }
+            LineIntersection::Collinear { intersection } => {
+                for point in [intersection.start, intersection.end] {
+                    crossings.push((a.index, point));
+                    crossings.push((b.index, point));
+                }
+            }
+        }
+    }
+    crossings
+}
+
+/// The coords of `ring`, with `crossings` inserted into the segments they are on
+fn node_ring(ring: &LineString<f64>, crossings: Vec<(usize, Coord<f64>)>) -> Vec<Coord<f64>> {
+    let mut on_segment: Vec<Vec<Coord<f64>>> = vec![vec![]; ring.lines().count()];
+    for (index, point) in crossings {
+        on_segment[index].push(point);
+    }
+    let distance = |from: Coord<f64>, to: &Coord<f64>| {
+        let delta = *to - from;
+        delta.x * delta.x + delta.y * delta.y
+    };
+    let mut coords = vec![];
+    for (line, mut points) in ring.lines().zip(on_segment) {
+        coords.push(line.start);
+        points.sort_by(|a, b| distance(line.start, a).total_cmp(&distance(line.start, b)));
+        for point in points {
+            if coords.last() != Some(&point) && point != line.end {
+                coords.push(point);
+            }
+        }
+    }
+    coords.push(ring.0[0]);
+    coords
+}
+
+/// Split a closed path into the simple loops it makes, by cutting out a loop each
+/// time the path comes back to somewhere it has already been
+fn split_loops(coords: Vec<Coord<f64>>) -> Vec<LineString<f64>> {
+    let key = |coord: &Coord<f64>| (coord.x.to_bits(), coord.y.to_bits());
+    let mut path: Vec<Coord<f64>> = vec![];
+    let mut seen: HashMap<(u64, u64), usize> = HashMap::default();
+    let mut loops = vec![];
+    for coord in coords {
+        match seen.get(&key(&coord)) {
+            Some(&start) => {
+                let mut ring: Vec<Coord<f64>> = path.drain(start..).collect();
+                for visited in ring.iter() {
+                    seen.remove(&key(visited));
+                }
+                ring.push(coord);
+                seen.insert(key(&coord), path.len());
+                path.push(coord);
+                let ring = LineString::new(ring);
+                if has_area(&ring) {
+                    loops.push(ring);
+                }
+            }
+            None => {
+                seen.insert(key(&coord), path.len());
+                path.push(coord);
+            }
+        }
+    }
+    loops
+}
+
+/// Sort simple loops into those which enclose area, and those which are holes in
+/// them, by how many other loops each is inside (the even-odd rule)
+fn nest_loops(loops: Vec<LineString<f64>>) -> (Vec<LineString<f64>>, Vec<LineString<f64>>) {
+    let polygons: Vec<Polygon<f64>> = loops
+        .iter()
+        .map(|ring| Polygon::new(ring.clone(), vec![]))
+        .collect();
+    let mut outers = vec![];
+    let mut inners = vec![];
+    for (i, ring) in loops.into_iter().enumerate() {
+        let Some(point) = polygons[i].interior_point() else {
+            continue;
+        };
+        let depth = polygons
+            .iter()
+            .enumerate()
+            .filter(|(j, other)| *j != i && other.contains(&point))
+            .count();
+        if depth % 2 == 0 {
+            outers.push(ring);
+        } else {
+            inners.push(ring);
+        }
+    }
+    (outers, inners)
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{polygon, Area};
+    use geo_validity_check::Valid;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn repair(polygon: Polygon<f64>) -> (Vec<Polygon<f64>>, BTreeSet<Reason>) {
+        let mut issues = BTreeSet::new();
+        let repaired = repair_polygon(polygon, &mut issues);
+        for polygon in repaired.iter() {
+            assert!(polygon.is_valid(), "{:?}", polygon.explain_invalidity());
+        }
+        (repaired, issues)
+    }
+
+    #[test]
+    fn valid_polygon_is_unchanged() {
+        let square = polygon![(x: 0., y: 0.), (x: 2., y: 0.), (x: 2., y: 2.), (x: 0., y: 2.)];
+        let (repaired, issues) = repair(square.clone());
+        assert_eq!(repaired, vec![square]);
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn bow_tie_is_split_in_two() {
+        let bow_tie = polygon![(x: 0., y: 0.), (x: 2., y: 2.), (x: 2., y: 0.), (x: 0., y: 2.)];
+        let (repaired, issues) = repair(bow_tie);
+        assert_eq!(repaired.len(), 2);
+        assert_eq!(repaired.iter().map(|p| p.unsigned_area()).sum::<f64>(), 2.0);
+        assert_eq!(issues, BTreeSet::from([Reason::SelfIntersectionFixed]));
+    }
+
+    #[test]
+    fn degenerate_rings_are_dropped() {
+        let line = polygon![(x: 0., y: 0.), (x: 1., y: 1.), (x: 2., y: 2.)];
+        let (repaired, issues) = repair(line);
+        assert!(repaired.is_empty());
+        assert_eq!(issues, BTreeSet::from([Reason::DegenerateRingDropped]));
+
+        let with_flat_hole = polygon!(
+            exterior: [(x: 0., y: 0.), (x: 4., y: 0.), (x: 4., y: 4.), (x: 0., y: 4.)],
+            interiors: [[(x: 1., y: 1.), (x: 2., y: 1.), (x: 1., y: 1.)]],
+        );
+        let (repaired, issues) = repair(with_flat_hole);
+        assert_eq!(repaired.len(), 1);
+        assert!(repaired[0].interiors().is_empty());
+        assert_eq!(issues, BTreeSet::from([Reason::DegenerateRingDropped]));
+    }
+
+    #[test]
+    fn holes_outside_are_dropped_and_crossing_holes_are_cut_out() {
+        let stray = polygon!(
+            exterior: [(x: 0., y: 0.), (x: 4., y: 0.), (x: 4., y: 4.), (x: 0., y: 4.)],
+            interiors: [[(x: 10., y: 10.), (x: 11., y: 10.), (x: 11., y: 11.)]],
+        );
+        let (repaired, issues) = repair(stray);
+        assert!(repaired[0].interiors().is_empty());
+        assert_eq!(issues, BTreeSet::from([Reason::StrayHoleDropped]));
+
+        let crossing = polygon!(
+            exterior: [(x: 0., y: 0.), (x: 4., y: 0.), (x: 4., y: 4.), (x: 0., y: 4.)],
+            interiors: [[(x: 3., y: 1.), (x: 5., y: 1.), (x: 5., y: 3.), (x: 3., y: 3.)]],
+        );
+        let (repaired, issues) = repair(crossing);
+        assert_eq!(
+            repaired.iter().map(|p| p.unsigned_area()).sum::<f64>(),
+            16.0 - 2.0
+        );
+        assert_eq!(issues, BTreeSet::from([Reason::CrossingRingsFixed]));
+    }
+}
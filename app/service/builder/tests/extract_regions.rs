@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+
+use builder::{
+    builder::{extract_regions, Sources},
+    filter::GreenTags,
+    nodes::NodeStoreKind,
+    region::{ElementKind, Region},
+    report::{Reason, Report},
+};
+use geo::{Area, Geometry};
+use pretty_assertions::assert_eq;
+
+/// A `.osm.pbf` in `tests/fixtures`, as described in the README there
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(format!("{}.osm.pbf", name))
+}
+
+fn extract(name: &str, sources: Sources) -> Vec<Region> {
+    extract_with(name, sources, NodeStoreKind::default())
+}
+
+fn extract_with(name: &str, sources: Sources, kind: NodeStoreKind) -> Vec<Region> {
+    extract_with_report(name, sources, kind).0
+}
+
+fn extract_with_report(name: &str, sources: Sources, kind: NodeStoreKind) -> (Vec<Region>, Report) {
+    let dir = tempfile::tempdir().unwrap();
+    let nodes = kind.create(Some(dir.path())).unwrap();
+    extract_regions(&fixture(name), &GreenTags::default(), sources, nodes).unwrap()
+}
+
+fn summary(regions: &[Region]) -> Vec<(i64, ElementKind, String)> {
+    regions
+        .iter()
+        .map(|r| (r.osm_id, r.osm_type, r.tag.clone()))
+        .collect()
+}
+
+#[test]
+fn ways_and_relations_become_regions() {
+    let regions = extract("park_and_gardens", Sources::All);
+    assert_eq!(
+        summary(&regions),
+        vec![
+            (100, ElementKind::Way, "leisure=park".to_string()),
+            (200, ElementKind::Relation, "leisure=park".to_string()),
+            (201, ElementKind::Relation, "natural=wood".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn relation_outer_ways_are_joined_and_inner_ways_become_holes() {
+    let regions = extract("park_and_gardens", Sources::All);
+    let Geometry::MultiPolygon(gardens) = &regions[1].geometry else {
+        panic!("expected a MultiPolygon, got {:?}", regions[1].geometry);
+    };
+    assert_eq!(gardens.0.len(), 1);
+    assert_eq!(gardens.0[0].interiors().len(), 1);
+    assert_eq!(gardens.unsigned_area(), 16.0 - 0.5);
+
+    let Geometry::MultiPolygon(woods) = &regions[2].geometry else {
+        panic!("expected a MultiPolygon, got {:?}", regions[2].geometry);
+    };
+    assert_eq!(woods.0.len(), 2);
+}
+
+#[test]
+fn sources_can_be_restricted() {
+    let direct = extract("park_and_gardens", Sources::Direct);
+    assert_eq!(
+        summary(&direct),
+        vec![(100, ElementKind::Way, "leisure=park".to_string())]
+    );
+
+    let relations = extract("park_and_gardens", Sources::Relations);
+    assert_eq!(
+        relations.iter().map(|r| r.osm_id).collect::<Vec<_>>(),
+        vec![200, 201]
+    );
+}
+
+#[test]
+fn synthetic_city_finds_all_parks() {
+    let regions = extract("synthetic_city_10", Sources::All);
+    let relations = regions
+        .iter()
+        .filter(|r| r.osm_type == ElementKind::Relation)
+        .count();
+    assert_eq!(relations, 10);
+    assert_eq!(regions.len() - relations, 30);
+}
+
+#[test]
+fn regions_are_in_element_order() {
+    let elements: Vec<(ElementKind, i64)> = extract("synthetic_city_10", Sources::All)
+        .iter()
+        .map(|r| (r.osm_type, r.osm_id))
+        .collect();
+    let mut sorted = elements.clone();
+    sorted.sort();
+    assert_eq!(elements, sorted);
+}
+
+#[test]
+fn all_node_stores_give_the_same_regions() {
+    let expected = extract("synthetic_city_10", Sources::All);
+    for kind in [
+        NodeStoreKind::Dense,
+        NodeStoreKind::Sorted,
+        NodeStoreKind::Mmap,
+    ] {
+        let regions = extract_with("synthetic_city_10", Sources::All, kind);
+        assert_eq!(
+            regions
+                .iter()
+                .map(|r| (r.osm_id, &r.geometry))
+                .collect::<Vec<_>>(),
+            expected
+                .iter()
+                .map(|r| (r.osm_id, &r.geometry))
+                .collect::<Vec<_>>(),
+            "{:?}",
+            kind
+        );
+    }
+}
+
+#[test]
+fn missing_nodes_and_ways_are_repaired_or_skipped() {
+    let (regions, report) = extract_with_report("clipped", Sources::All, NodeStoreKind::default());
+    assert_eq!(
+        regions.iter().map(|r| r.osm_id).collect::<Vec<_>>(),
+        vec![100, 200, 202]
+    );
+    assert_eq!(regions[0].geometry.unsigned_area(), 1.0);
+    // only the closed outer Way is left
+    assert_eq!(regions[2].geometry.unsigned_area(), 0.5);
+
+    assert_eq!(
+        report.elements(Reason::MissingNodesDropped),
+        &[(ElementKind::Way, 100)]
+    );
+    assert_eq!(
+        report.elements(Reason::MissingNodesSkipped),
+        &[(ElementKind::Way, 101)]
+    );
+    let mut missing_ways = report.elements(Reason::MissingMemberWays).to_vec();
+    missing_ways.sort();
+    assert_eq!(
+        missing_ways,
+        vec![
+            (ElementKind::Relation, 200),
+            (ElementKind::Relation, 201),
+            (ElementKind::Relation, 202)
+        ]
+    );
+    assert_eq!(
+        report.elements(Reason::UnclosedRingDropped),
+        &[(ElementKind::Relation, 202)]
+    );
+    assert_eq!(
+        report.elements(Reason::NoClosedOuterRing),
+        &[(ElementKind::Relation, 201)]
+    );
+}
+
+#[test]
+fn invalid_polygons_are_repaired_before_writing() {
+    let (regions, report) = extract_with_report("invalid", Sources::All, NodeStoreKind::default());
+
+    let Geometry::MultiPolygon(bow_tie) = &regions[0].geometry else {
+        panic!("expected a MultiPolygon, got {:?}", regions[0].geometry);
+    };
+    assert_eq!(bow_tie.0.len(), 2);
+    assert_eq!(
+        report.elements(Reason::SelfIntersectionFixed),
+        &[(ElementKind::Way, 100)]
+    );
+    assert_eq!(regions[1].geometry.unsigned_area(), 0.5);
+    assert_eq!(
+        report.elements(Reason::UnclosedRingClosed),
+        &[(ElementKind::Way, 101)]
+    );
+}
@@ -0,0 +1,47 @@
+use core_geo::union::bench::{partition, partition_by_merging};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use geo::Polygon;
+
+/// A `size` x `size` grid of unit squares, each overlapping its neighbours, so
+/// that they are all one connected cluster
+fn overlapping_grid(size: usize) -> Vec<Polygon> {
+    let mut squares = vec![];
+    for i in 0..size {
+        for j in 0..size {
+            let (x, y) = (i as f64 * 0.75, j as f64 * 0.75);
+            squares.push(Polygon::new(
+                vec![
+                    (x, y),
+                    (x + 1.0, y),
+                    (x + 1.0, y + 1.0),
+                    (x, y + 1.0),
+                    (x, y),
+                ]
+                .into(),
+                vec![],
+            ));
+        }
+    }
+    squares
+}
+
+fn partition_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("partition overlapping_grid");
+    for size in [10, 30, 60] {
+        let polygons = overlapping_grid(size);
+        group.bench_with_input(BenchmarkId::new("union_find", size), &polygons, |b, p| {
+            b.iter(|| partition(p))
+        });
+        group.bench_with_input(BenchmarkId::new("merging", size), &polygons, |b, p| {
+            b.iter(|| partition_by_merging(p))
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = partition_benchmark
+}
+criterion_main!(benches);
@@ -1,53 +1,403 @@
-use cavalier_contours::polyline::{
-    PlineCreation, PlineOffsetOptions, PlineSource, PlineVertex, Polyline,
+use std::{
+    f64::consts::{PI, TAU},
+    panic::{self, AssertUnwindSafe},
 };
-use geo::{Coord, CoordsIter, LineString, MultiPolygon, Polygon};
-use geo_validity_check::Valid;
-use tracing::{debug, trace};
 
+use geo::{
+    orient::Direction, Coord, LineString, MapCoords, MultiPolygon, Orient, Polygon,
+    RemoveRepeatedPoints,
+};
+use tracing::{trace, warn};
+
+use cavalier_contours::polyline::PlineSource;
+
+use crate::{cavalier, projection::LocalProjection};
+
+/// How far the lines approximating the arcs at convex corners can be from the
+/// arcs, as a fraction of the buffer distance
+const ARC_ERROR: f64 = 0.01;
+
+/// Tolerances to offset with, from finest to coarsest, until cavalier_contours
+/// doesn't panic
+const OFFSET_EPS: [f64; 3] = [cavalier::EPS, 1e-7, 1e-5];
+
+/// Grow `poly` by `distance`, or shrink it if `distance` is negative, in the same
+/// units as its coords. Holes shrink as the polygon grows, and grow as it shrinks,
+/// so shrinking can split the polygon or remove it entirely. If cavalier_contours
+/// panics at every tolerance, the result is empty.
+///
+/// Exteriors come out counter-clockwise and holes clockwise, whatever the winding
+/// of `poly`.
 pub fn buffer_polygon(poly: &Polygon<f64>, distance: f64) -> MultiPolygon<f64> {
-    let coords_iter = poly.exterior().coords_iter();
-    let vertex_iter = coords_iter.map(|c| PlineVertex::new(c.x, c.y, 0.0));
-    let polyline = Polyline::from_iter(vertex_iter, true);
-
-    let offsetted = polyline.parallel_offset_opt(
-        -1.0 * distance,
-        &PlineOffsetOptions {
-            handle_self_intersects: true,
-            ..Default::default()
-        },
+    buffer_multi_polygon(&MultiPolygon::new(vec![poly.clone()]), distance)
+}
+
+/// As `buffer_polygon`, merging polygons which grow into each other. The polygons
+/// shouldn't overlap to start with.
+pub fn buffer_multi_polygon(poly: &MultiPolygon<f64>, distance: f64) -> MultiPolygon<f64> {
+    let shape = cavalier::shape(poly);
+    // cavalier_contours offsets counter-clockwise rings inwards, and can panic on
+    // nearly coincident slices, which coarser tolerances merge
+    let offset = |eps: f64| shape.parallel_offset(-distance, cavalier::offset_options(eps));
+    let Some(offset) = with_coarser_tolerances("offset", offset) else {
+        warn!("Offset failed at every tolerance, leaving the polygon out");
+        return MultiPolygon::new(vec![]);
+    };
+    trace!(
+        "buffered into {} outer rings and {} holes",
+        offset.ccw_plines.len(),
+        offset.cw_plines.len()
     );
-    // let offsetted = vec![polyline.clone()];
 
-    trace!("Number of offsetted polylines: {}", offsetted.len());
+    let arc_error = (distance.abs() * ARC_ERROR).max(cavalier::EPS);
+    MultiPolygon::new(cavalier::polygons_of_shape(&offset, arc_error)).orient(Direction::Default)
+}
 
-    fn from_polyline(polyline: Polyline) -> Polygon {
-        let mut coords: Vec<Coord> = polyline
-            .iter_vertexes()
-            .map(|v| Coord::from((v.x, v.y)))
-            .collect();
-        coords.push(coords[0]);
-        let poly = Polygon::new(LineString::from(coords), vec![]);
-        if !poly.is_valid() {
-            debug!("poly is invalid {:?}", poly.explain_invalidity());
-            debug!("polyline: {:?}", polyline);
-            debug!("poly: {:?}", poly);
+/// Run `offset` with each of `OFFSET_EPS` in turn until it doesn't panic, or give
+/// `None` if it panics with them all
+fn with_coarser_tolerances<T>(what: &str, offset: impl Fn(f64) -> T) -> Option<T> {
+    OFFSET_EPS.iter().find_map(|eps| {
+        let offset = panic::catch_unwind(AssertUnwindSafe(|| offset(*eps)));
+        if offset.is_err() {
+            warn!("Panic detected in {} with a tolerance of {}", what, eps);
         }
-        poly
-    }
+        offset.ok()
+    })
+}
 
-    let polygons = offsetted.into_iter().map(from_polyline).collect::<Vec<_>>();
-    MultiPolygon::new(polygons)
+/// As `buffer_polygon`, for lon/lat `poly` and a distance in `metres`, so that it
+/// means the same at any latitude
+pub fn buffer_polygon_metres(poly: &Polygon<f64>, metres: f64) -> MultiPolygon<f64> {
+    buffer_multi_polygon_metres(&MultiPolygon::new(vec![poly.clone()]), metres)
 }
 
-pub fn buffer_multi_polygon(poly: &MultiPolygon<f64>, distance: f64) -> MultiPolygon<f64> {
-    let buffered = poly
+/// As `buffer_multi_polygon`, for lon/lat `poly` and a distance in `metres`. It's
+/// buffered in a projection centred on it, so is best kept to a city or so across.
+pub fn buffer_multi_polygon_metres(poly: &MultiPolygon<f64>, metres: f64) -> MultiPolygon<f64> {
+    let Some(projection) = LocalProjection::centred_on(poly) else {
+        return MultiPolygon::new(vec![]);
+    };
+    let projected = poly.map_coords(|c| projection.project(c));
+    buffer_multi_polygon(&projected, metres).map_coords(|c| projection.unproject(c))
+}
+
+/// How the ends of a buffered line are finished
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Cap {
+    /// a semicircle around each end
+    #[default]
+    Round,
+    /// cut square across each end
+    Flat,
+}
+
+/// The corridor within `metres` of a lon/lat `line`, such as a route. Bends are
+/// always rounded; `cap` is only for the ends. A flat cap is left round where
+/// another part of the line passes within `metres` of that end.
+/// If cavalier_contours panics at every tolerance, the corridor is empty.
+pub fn buffer_line_string(line: &LineString<f64>, metres: f64, cap: Cap) -> MultiPolygon<f64> {
+    let line = line.remove_repeated_points();
+    let Some(projection) = LocalProjection::centred_on(&line) else {
+        return MultiPolygon::new(vec![]);
+    };
+    if metres <= 0.0 {
+        return MultiPolygon::new(vec![]);
+    }
+    let coords: Vec<Coord<f64>> = line.coords().map(|c| projection.project(*c)).collect();
+    let corridor = match (&coords[..], cap) {
+        ([_], Cap::Flat) => vec![],
+        ([centre], Cap::Round) => vec![circle(*centre, metres)],
+        _ => offset_there_and_back(&coords, metres, cap),
+    };
+    MultiPolygon::new(corridor)
+        .map_coords(|c| projection.unproject(c))
+        .orient(Direction::Default)
+}
+
+/// Buffer an open line of at least two `coords` by offsetting a closed polyline
+/// which goes along it and back again. That has no area, so offsetting it outwards
+/// gives both sides of the corridor, with a round join where it turns back at each
+/// end as the cap.
+fn offset_there_and_back(coords: &[Coord<f64>], distance: f64, cap: Cap) -> Vec<Polygon<f64>> {
+    let there_and_back = cavalier::there_and_back(coords);
+    // the polyline overlaps itself all the way along, so its own intersections need
+    // handled, and it's wound clockwise whichever way the line goes
+    let offset = |eps: f64| {
+        there_and_back.parallel_offset_opt(-distance, &cavalier::pline_offset_options(eps))
+    };
+    let Some(mut offset) = with_coarser_tolerances("line offset", offset) else {
+        warn!("Line offset failed at every tolerance, leaving the corridor empty");
+        return vec![];
+    };
+    if cap == Cap::Flat {
+        let ends = [coords[0], coords[coords.len() - 1]];
+        for polyline in offset.iter_mut() {
+            cavalier::flatten_caps(polyline, &ends, distance);
+        }
+    }
+    trace!("buffered line into {} rings", offset.len());
+
+    let arc_error = (distance * ARC_ERROR).max(cavalier::EPS);
+    let mut rings: Vec<_> = offset
         .iter()
-        .flat_map(|p| {
-            let buffered = buffer_polygon(p, distance);
-            let buffered_polygons: Vec<Polygon> = buffered.into_iter().collect();
-            buffered_polygons
+        .map(|polyline| {
+            polyline
+                .arcs_to_approx_lines(arc_error)
+                .unwrap_or_else(|| polyline.clone())
         })
-        .collect::<Vec<_>>();
-    MultiPolygon::new(buffered)
+        .collect();
+    // the line is all in one piece, so the corridor is too, and any other rings
+    // are holes where the line loops around
+    rings.sort_by(|a, b| b.area().abs().total_cmp(&a.area().abs()));
+    let holes = rings.split_off(rings.len().min(1));
+    cavalier::polygons(&rings, &holes)
+}
+
+/// A circle approximated with lines no further than `ARC_ERROR` of `radius`
+/// inside it
+fn circle(centre: Coord<f64>, radius: f64) -> Polygon<f64> {
+    let sides = (PI / (1.0 - ARC_ERROR).acos()).ceil() as usize;
+    let mut coords: Vec<Coord<f64>> = (0..sides)
+        .map(|i| {
+            let angle = i as f64 / sides as f64 * TAU;
+            centre
+                + Coord {
+                    x: radius * angle.cos(),
+                    y: radius * angle.sin(),
+                }
+        })
+        .collect();
+    coords.push(coords[0]);
+    Polygon::new(coords.into(), vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{polygon, Area, BoundingRect, Contains, HaversineDistance, Point, Winding};
+    use geo_validity_check::Valid;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn with_hole() -> Polygon<f64> {
+        polygon!(
+            exterior: [(x: 0., y: 0.), (x: 10., y: 0.), (x: 10., y: 10.), (x: 0., y: 10.)],
+            interiors: [[(x: 3., y: 3.), (x: 7., y: 3.), (x: 7., y: 7.), (x: 3., y: 7.)]],
+        )
+    }
+
+    #[test]
+    fn coarser_tolerances_are_tried_until_one_works() {
+        let coarsest = OFFSET_EPS[OFFSET_EPS.len() - 1];
+        let offset = with_coarser_tolerances("test", |eps| {
+            assert_eq!(eps, coarsest, "too fine");
+            eps
+        });
+        assert_eq!(offset, Some(coarsest));
+        let offset = with_coarser_tolerances("test", |eps| -> f64 { panic!("{}", eps) });
+        assert_eq!(offset, None);
+    }
+
+    #[test]
+    fn growing_shrinks_holes() {
+        let buffered = buffer_polygon(&with_hole(), 1.0);
+        assert_eq!(buffered.0.len(), 1);
+        assert!(buffered.is_valid(), "{:?}", buffered.explain_invalidity());
+        let hole = &buffered.0[0].interiors()[0];
+        assert!((Polygon::new(hole.clone(), vec![]).unsigned_area() - 4.0).abs() < 1e-9);
+        assert!(buffered.contains(&with_hole()));
+    }
+
+    #[test]
+    fn shrinking_can_fill_holes_and_split() {
+        let buffered = buffer_polygon(&with_hole(), -1.0);
+        assert_eq!(buffered.0.len(), 1);
+        assert!(buffered.is_valid(), "{:?}", buffered.explain_invalidity());
+        // the hole's corners are rounded, and approximated by lines inside the arcs
+        let expected = 64.0 - (36.0 - 4.0 + std::f64::consts::PI);
+        assert!((buffered.unsigned_area() - expected).abs() < 0.05);
+
+        // the sides are 3 wide, so vanish
+        assert!(buffer_polygon(&with_hole(), -2.0).0.is_empty());
+
+        let dumbbell = polygon![
+            (x: 0., y: 0.), (x: 4., y: 0.), (x: 4., y: 1.5), (x: 6., y: 1.5),
+            (x: 6., y: 0.), (x: 10., y: 0.), (x: 10., y: 4.), (x: 6., y: 4.),
+            (x: 6., y: 2.5), (x: 4., y: 2.5), (x: 4., y: 4.), (x: 0., y: 4.),
+        ];
+        assert_eq!(buffer_polygon(&dumbbell, -1.0).0.len(), 2);
+    }
+
+    #[test]
+    fn winding_is_normalised() {
+        let mut reversed = with_hole();
+        reversed.exterior_mut(|exterior| exterior.0.reverse());
+        reversed.interiors_mut(|interiors| interiors[0].0.reverse());
+        for poly in [with_hole(), reversed] {
+            let buffered = buffer_polygon(&poly, 0.5);
+            assert!(buffered.0[0].exterior().is_ccw());
+            assert!(buffered.0[0].interiors()[0].is_cw());
+            assert!(buffered.unsigned_area() > poly.unsigned_area());
+        }
+    }
+
+    #[test]
+    fn polygons_growing_together_are_merged() {
+        let apart = MultiPolygon::new(vec![
+            polygon![(x: 0., y: 0.), (x: 1., y: 0.), (x: 1., y: 1.), (x: 0., y: 1.)],
+            polygon![(x: 2., y: 0.), (x: 3., y: 0.), (x: 3., y: 1.), (x: 2., y: 1.)],
+        ]);
+        assert_eq!(buffer_multi_polygon(&apart, 0.25).0.len(), 2);
+        let merged = buffer_multi_polygon(&apart, 1.0);
+        assert_eq!(merged.0.len(), 1);
+        assert!(merged.is_valid(), "{:?}", merged.explain_invalidity());
+    }
+
+    #[test]
+    fn metres_mean_the_same_at_any_latitude() {
+        for (lon, lat) in [(0.0, 0.0), (-3.19, 55.95), (139.69, 35.69), (18.0, -70.0)] {
+            // a 100m square, more or less, so buffering by 20m reaches 70m from the
+            // middle of it both north and east
+            let centre = Point::new(lon, lat);
+            let projection = LocalProjection::new(centre.0);
+            let square = polygon![
+                (x: -50., y: -50.), (x: 50., y: -50.), (x: 50., y: 50.), (x: -50., y: 50.),
+            ]
+            .map_coords(|c| projection.unproject(c));
+            let bounds = buffer_polygon_metres(&square, 20.0)
+                .bounding_rect()
+                .unwrap();
+            let north = centre.haversine_distance(&Point::new(lon, bounds.max().y));
+            let east = centre.haversine_distance(&Point::new(bounds.max().x, lat));
+            assert!((north - 70.0).abs() < 0.01, "{} at {:?}", north, centre);
+            assert!((east - 70.0).abs() < 0.01, "{} at {:?}", east, centre);
+        }
+    }
+
+    /// the area of `corridor` in metres, in the projection `line` was buffered in
+    fn area_in_metres(line: &LineString<f64>, corridor: &MultiPolygon<f64>) -> f64 {
+        let projection = LocalProjection::centred_on(line).unwrap();
+        corridor
+            .map_coords(|c| projection.project(c))
+            .unsigned_area()
+    }
+
+    #[test]
+    fn line_buffers_have_caps() {
+        // 100m east, then 100m north
+        let projection = LocalProjection::new((-3.19, 55.95).into());
+        let straight: LineString<f64> = LineString::from(vec![(0.0, 0.0), (100.0, 0.0)])
+            .map_coords(|c| projection.unproject(c));
+        let bent: LineString<f64> =
+            LineString::from(vec![(0.0, 0.0), (100.0, 0.0), (100.0, 100.0)])
+                .map_coords(|c| projection.unproject(c));
+        let cap_area = PI * 10.0 * 10.0;
+
+        let round = buffer_line_string(&straight, 10.0, Cap::Round);
+        assert_eq!(round.0.len(), 1);
+        assert!((area_in_metres(&straight, &round) - (2000.0 + cap_area)).abs() < 10.0);
+
+        let flat = buffer_line_string(&straight, 10.0, Cap::Flat);
+        assert!((area_in_metres(&straight, &flat) - 2000.0).abs() < 1.0);
+
+        // the outside of the bend is a quarter circle, and the inside overlaps
+        let bent_flat = buffer_line_string(&bent, 10.0, Cap::Flat);
+        assert_eq!(bent_flat.0.len(), 1);
+        let expected = 4000.0 + cap_area / 4.0 - 100.0;
+        assert!((area_in_metres(&bent, &bent_flat) - expected).abs() < 10.0);
+        assert!(bent_flat.is_valid(), "{:?}", bent_flat.explain_invalidity());
+    }
+
+    #[test]
+    fn looping_lines_leave_holes() {
+        // round a 100m square, then back across where it started
+        let projection = LocalProjection::new((-3.19, 55.95).into());
+        let looping: LineString<f64> = LineString::from(vec![
+            (0.0, 0.0),
+            (100.0, 0.0),
+            (100.0, 100.0),
+            (0.0, 100.0),
+            (0.0, -20.0),
+        ])
+        .map_coords(|c| projection.unproject(c));
+        for cap in [Cap::Round, Cap::Flat] {
+            let corridor = buffer_line_string(&looping, 10.0, cap);
+            assert_eq!(corridor.0.len(), 1);
+            assert!(corridor.is_valid(), "{:?}", corridor.explain_invalidity());
+            let holes = corridor.0[0].interiors();
+            assert_eq!(holes.len(), 1);
+            let hole = Polygon::new(holes[0].clone(), vec![]);
+            let hole_area = area_in_metres(&looping, &MultiPolygon::new(vec![hole]));
+            assert!((hole_area - 80.0 * 80.0).abs() < 1.0, "{}", hole_area);
+        }
+    }
+
+    #[test]
+    fn empty_lines_have_no_buffer() {
+        let empty = LineString::new(vec![]);
+        assert!(buffer_line_string(&empty, 10.0, Cap::Round).0.is_empty());
+        let point: LineString<f64> = vec![(1.0, 1.0), (1.0, 1.0)].into();
+        assert!(buffer_line_string(&point, 10.0, Cap::Flat).0.is_empty());
+        assert_eq!(buffer_line_string(&point, 10.0, Cap::Round).0.len(), 1);
+    }
+
+    /// a star-shaped polygon around the origin, with a hole if `hole` is given
+    fn star(radii: &[f64], hole: Option<f64>) -> Polygon<f64> {
+        let ring = |radii: &[f64]| {
+            let mut coords: Vec<(f64, f64)> = radii
+                .iter()
+                .enumerate()
+                .map(|(i, r)| {
+                    let angle = i as f64 / radii.len() as f64 * std::f64::consts::TAU;
+                    (r * angle.cos(), r * angle.sin())
+                })
+                .collect();
+            coords.push(coords[0]);
+            coords
+        };
+        let holes = hole
+            .map(|size| ring(&[size, size, size, size]).into())
+            .into_iter()
+            .collect();
+        Polygon::new(ring(radii).into(), holes)
+    }
+
+    proptest! {
+        #[test]
+        fn line_buffers_are_valid_and_cover_the_line(
+            coords in prop::collection::vec((-100.0..100.0f64, -100.0..100.0f64), 2..12),
+            metres in 1.0..30.0f64,
+            round in any::<bool>(),
+        ) {
+            let projection = LocalProjection::new((-3.19, 55.95).into());
+            let line: LineString<f64> =
+                LineString::from(coords).map_coords(|c| projection.unproject(c));
+            let cap = if round { Cap::Round } else { Cap::Flat };
+            let corridor = buffer_line_string(&line, metres, cap);
+            prop_assert!(corridor.is_valid(), "{:?}", corridor.explain_invalidity());
+            // the middle of each segment, away from any flat caps
+            for segment in line.lines() {
+                let middle = (segment.start + segment.end) / 2.0;
+                prop_assert!(corridor.contains(&middle), "{:?} not in corridor", middle);
+            }
+        }
+
+        #[test]
+        fn area_grows_with_distance(
+            radii in prop::collection::vec(2.0..10.0f64, 3..12),
+            hole in prop::option::of(0.5..1.5f64),
+            distance in -1.0..2.0f64,
+            step in 0.1..1.0f64,
+        ) {
+            let poly = star(&radii, hole);
+            let smaller = buffer_polygon(&poly, distance).unsigned_area();
+            let larger = buffer_polygon(&poly, distance + step).unsigned_area();
+            prop_assert!(smaller < larger, "{} then {}", smaller, larger);
+            if distance > 0.0 {
+                prop_assert!(smaller > poly.unsigned_area());
+            } else if distance + step < 0.0 {
+                prop_assert!(larger < poly.unsigned_area());
+            }
+        }
+    }
 }
@@ -0,0 +1,196 @@
+use cavalier_contours::{
+    polyline::{PlineOffsetOptions, PlineSource, PlineSourceMut, Polyline},
+    shape_algorithms::{Shape, ShapeOffsetOptions},
+};
+use geo::{coordinate_position::CoordPos, Coord, CoordinatePosition, LineString, Polygon};
+
+/// Positions closer than this are treated as equal. cavalier_contours defaults to
+/// 1e-5, which is around a metre in degrees, so far too coarse for OSM data.
+pub(crate) const EPS: f64 = 1e-9;
+
+/// Offset options treating positions closer than `eps` as equal
+pub(crate) fn offset_options(eps: f64) -> ShapeOffsetOptions<f64> {
+    ShapeOffsetOptions {
+        pos_equal_eps: eps,
+        offset_dist_eps: eps,
+        slice_join_eps: eps,
+    }
+}
+
+/// Offset options for a single polyline which may intersect itself, treating
+/// positions closer than `eps` as equal
+pub(crate) fn pline_offset_options(eps: f64) -> PlineOffsetOptions<'static, f64> {
+    PlineOffsetOptions {
+        aabb_index: None,
+        handle_self_intersects: true,
+        pos_equal_eps: eps,
+        slice_join_eps: eps,
+        offset_dist_eps: eps,
+    }
+}
+
+/// A closed polyline going along the open line through `coords` and back again
+pub(crate) fn there_and_back(coords: &[Coord<f64>]) -> Polyline<f64> {
+    let mut polyline = Polyline::new_closed();
+    let back = coords
+        .iter()
+        .rev()
+        .skip(1)
+        .take(coords.len().saturating_sub(2));
+    for coord in coords.iter().chain(back) {
+        polyline.add(coord.x, coord.y, 0.0);
+    }
+    polyline
+}
+
+/// Straighten the semicircular arcs which an offset `distance` from `there_and_back`
+/// has around any of the line's `ends`, so that the corridor is cut square there
+pub(crate) fn flatten_caps(polyline: &mut Polyline<f64>, ends: &[Coord<f64>], distance: f64) {
+    let eps = distance * 1e-6;
+    for i in 0..polyline.vertex_count() {
+        let start = polyline.at(i);
+        let end = polyline.at(polyline.fwd_wrapping_index(i, 1));
+        let middle = Coord::from(((start.x + end.x) / 2.0, (start.y + end.y) / 2.0));
+        let around_end = ends
+            .iter()
+            .any(|e| (middle.x - e.x).hypot(middle.y - e.y) < eps);
+        if (start.bulge.abs() - 1.0).abs() < 1e-6 && around_end {
+            polyline.set(i, start.x, start.y, 0.0);
+        }
+    }
+}
+
+/// A closed polyline from a ring, wound counter-clockwise if `ccw`, otherwise
+/// clockwise
+pub(crate) fn polyline(ring: &LineString<f64>, ccw: bool) -> Polyline<f64> {
+    let mut polyline = Polyline::new_closed();
+    let coords = &ring.0;
+    let open = match (coords.first(), coords.last()) {
+        (Some(first), Some(last)) if coords.len() > 1 && first == last => {
+            &coords[..coords.len() - 1]
+        }
+        _ => &coords[..],
+    };
+    for coord in open {
+        polyline.add(coord.x, coord.y, 0.0);
+    }
+    if (polyline.area() > 0.0) != ccw {
+        polyline.invert_direction_mut();
+    }
+    polyline
+}
+
+pub(crate) fn ring(polyline: &Polyline<f64>) -> LineString<f64> {
+    let mut coords: Vec<Coord<f64>> = polyline
+        .iter_vertexes()
+        .map(|v| Coord::from((v.x, v.y)))
+        .collect();
+    if let Some(first) = coords.first().copied() {
+        coords.push(first);
+    }
+    LineString::from(coords)
+}
+
+/// Exteriors counter-clockwise and holes clockwise, as cavalier_contours expects.
+/// The polygons shouldn't overlap.
+pub(crate) fn shape<'a>(polygons: impl IntoIterator<Item = &'a Polygon<f64>>) -> Shape<f64> {
+    Shape::from_plines(polygons.into_iter().flat_map(|polygon| {
+        std::iter::once(polyline(polygon.exterior(), true))
+            .chain(polygon.interiors().iter().map(|hole| polyline(hole, false)))
+    }))
+}
+
+/// Polygons from outer rings and holes, each hole going in the smallest outer ring
+/// which contains it, so not in an island inside the hole. Holes which aren't in
+/// any outer ring are left out.
+pub(crate) fn polygons(exteriors: &[Polyline<f64>], holes: &[Polyline<f64>]) -> Vec<Polygon<f64>> {
+    let (mut polygons, areas): (Vec<Polygon<f64>>, Vec<f64>) = exteriors
+        .iter()
+        .filter(|exterior| exterior.vertex_count() >= 3)
+        .map(|exterior| (Polygon::new(ring(exterior), vec![]), exterior.area().abs()))
+        .unzip();
+    let outers = polygons.clone();
+    for hole in holes.iter().filter(|hole| hole.vertex_count() >= 3) {
+        let area = hole.area().abs();
+        let hole = ring(hole);
+        // holes can touch their outer ring, but not cross it, so any vertex off the
+        // outer ring says which side the hole is on
+        let surrounds = |outer: &Polygon<f64>| {
+            hole.0
+                .iter()
+                .map(|coord| outer.coordinate_position(coord))
+                .find(|position| *position != CoordPos::OnBoundary)
+                == Some(CoordPos::Inside)
+        };
+        let smallest = (0..polygons.len())
+            .filter(|i| areas[*i] > area && surrounds(&outers[*i]))
+            .min_by(|a, b| areas[*a].total_cmp(&areas[*b]));
+        if let Some(i) = smallest {
+            polygons[i].interiors_push(hole);
+        }
+    }
+    polygons
+}
+
+/// Polygons from a shape, with any arcs approximated by lines no further than
+/// `arc_error` from them
+pub(crate) fn polygons_of_shape(shape: &Shape<f64>, arc_error: f64) -> Vec<Polygon<f64>> {
+    let lines = |polyline: &Polyline<f64>| {
+        polyline
+            .arcs_to_approx_lines(arc_error)
+            .unwrap_or_else(|| polyline.clone())
+    };
+    let exteriors: Vec<Polyline<f64>> = shape
+        .ccw_plines
+        .iter()
+        .map(|p| lines(&p.polyline))
+        .collect();
+    let holes: Vec<Polyline<f64>> = shape.cw_plines.iter().map(|p| lines(&p.polyline)).collect();
+    polygons(&exteriors, &holes)
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{polygon, Area};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn holes_go_in_the_smallest_outer_ring() {
+        let ring = |x: f64, size: f64, ccw: bool| {
+            let square: LineString<f64> = vec![
+                (x, x),
+                (x + size, x),
+                (x + size, x + size),
+                (x, x + size),
+                (x, x),
+            ]
+            .into();
+            polyline(&square, ccw)
+        };
+        // an island in a lake in an island
+        let exteriors = [ring(0.0, 10.0, true), ring(4.0, 2.0, true)];
+        let holes = [ring(2.0, 6.0, false)];
+        let polygons = polygons(&exteriors, &holes);
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(polygons[0].interiors().len(), 1);
+        assert_eq!(polygons[1].interiors().len(), 0);
+    }
+
+    #[test]
+    fn shape_round_trips_polygon_with_hole() {
+        let with_hole = polygon!(
+            exterior: [(x: 0., y: 0.), (x: 0., y: 4.), (x: 4., y: 4.), (x: 4., y: 0.)],
+            interiors: [[(x: 1., y: 1.), (x: 3., y: 1.), (x: 3., y: 3.), (x: 1., y: 3.)]],
+        );
+        let shape = shape([&with_hole]);
+        assert_eq!(shape.ccw_plines.len(), 1);
+        assert_eq!(shape.cw_plines.len(), 1);
+
+        let polygons = polygons_of_shape(&shape, EPS);
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0].interiors().len(), 1);
+        assert_eq!(polygons[0].unsigned_area(), 12.0);
+    }
+}
@@ -2,8 +2,19 @@ use geo::Rect;
 use serde::Deserialize;
 
 pub mod buffer;
+mod cavalier;
+pub mod projection;
+pub mod tiled_union;
 pub mod union;
 
+/// The FlatGeobuf layer name the builder gives regions it has already dissolved,
+/// so that the API knows not to union them again
+pub const DISSOLVED_LAYER: &str = "dissolved";
+
+/// The boolean property the builder sets on each dissolved area, true where a
+/// fallback was needed to union it, so that it may not be exact
+pub const DEGRADED_PROPERTY: &str = "degraded";
+
 #[derive(Deserialize, Debug)]
 pub struct Bounds {
     pub sw_lat: f64,
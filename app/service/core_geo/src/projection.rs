@@ -0,0 +1,126 @@
+use geo::{BoundingRect, Coord, Rect};
+
+/// The mean radius of the Earth, in metres, as used by geo
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// A spherical azimuthal equidistant projection from lon/lat degrees to metres,
+/// centred on `origin`. Distances from the origin are exact, and distortion stays
+/// well under 1% within a few hundred kilometres of it, which is plenty for
+/// buffering a city's worth of data.
+#[derive(Clone, Copy, Debug)]
+pub struct LocalProjection {
+    /// in radians
+    lon: f64,
+    lat: f64,
+}
+
+impl LocalProjection {
+    pub fn new(origin: Coord<f64>) -> Self {
+        LocalProjection {
+            lon: origin.x.to_radians(),
+            lat: origin.y.to_radians(),
+        }
+    }
+
+    /// Centred on the middle of `geometry`'s bounds, or `None` if it's empty
+    pub fn centred_on<G: BoundingRect<f64>>(geometry: &G) -> Option<Self>
+    where
+        G::Output: Into<Option<Rect<f64>>>,
+    {
+        let bounds: Option<Rect<f64>> = geometry.bounding_rect().into();
+        bounds.map(|bounds| Self::new(bounds.center()))
+    }
+
+    /// lon/lat degrees to metres east and north of the origin
+    pub fn project(&self, coord: Coord<f64>) -> Coord<f64> {
+        let (lon, lat) = (coord.x.to_radians(), coord.y.to_radians());
+        let d_lon = lon - self.lon;
+        // the angle from the origin, by the haversine formula as it's accurate for
+        // short distances
+        let haversine = ((lat - self.lat) / 2.0).sin().powi(2)
+            + self.lat.cos() * lat.cos() * (d_lon / 2.0).sin().powi(2);
+        let c = 2.0 * haversine.sqrt().min(1.0).asin();
+        let k = if c.abs() < 1e-12 { 1.0 } else { c / c.sin() };
+        Coord {
+            x: EARTH_RADIUS * k * lat.cos() * d_lon.sin(),
+            y: EARTH_RADIUS
+                * k
+                * (self.lat.cos() * lat.sin() - self.lat.sin() * lat.cos() * d_lon.cos()),
+        }
+    }
+
+    /// metres east and north of the origin to lon/lat degrees
+    pub fn unproject(&self, coord: Coord<f64>) -> Coord<f64> {
+        let rho = coord.x.hypot(coord.y);
+        if rho < 1e-9 {
+            return Coord {
+                x: self.lon.to_degrees(),
+                y: self.lat.to_degrees(),
+            };
+        }
+        let c = rho / EARTH_RADIUS;
+        let lat = (c.cos() * self.lat.sin() + coord.y * c.sin() * self.lat.cos() / rho).asin();
+        let lon = self.lon
+            + (coord.x * c.sin())
+                .atan2(rho * self.lat.cos() * c.cos() - coord.y * self.lat.sin() * c.sin());
+        Coord {
+            x: lon.to_degrees(),
+            y: lat.to_degrees(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{HaversineDistance, Point};
+
+    use super::*;
+
+    /// whether `a` and `b` are within `tolerance` of each other on both axes
+    fn close(a: Coord<f64>, b: Coord<f64>, tolerance: f64) -> bool {
+        (a.x - b.x).abs() < tolerance && (a.y - b.y).abs() < tolerance
+    }
+
+    #[test]
+    fn projection_round_trips() {
+        let edinburgh = Coord { x: -3.19, y: 55.95 };
+        let projection = LocalProjection::new(edinburgh);
+        assert!(close(
+            projection.project(edinburgh),
+            Coord { x: 0.0, y: 0.0 },
+            1e-9
+        ));
+        for coord in [
+            Coord { x: -3.2, y: 55.9 },
+            Coord { x: -2.0, y: 56.5 },
+            Coord { x: -3.19, y: 54.0 },
+        ] {
+            assert!(close(
+                projection.unproject(projection.project(coord)),
+                coord,
+                1e-9
+            ));
+        }
+    }
+
+    #[test]
+    fn distances_from_the_origin_are_metres() {
+        for origin in [
+            Coord { x: -3.19, y: 55.95 },
+            Coord {
+                x: 139.69,
+                y: 35.69,
+            },
+            Coord { x: 0.0, y: 0.0 },
+        ] {
+            let projection = LocalProjection::new(origin);
+            let east = Coord {
+                x: origin.x + 0.01,
+                y: origin.y + 0.005,
+            };
+            let projected = projection.project(east);
+            let metres = Point::from(origin).haversine_distance(&Point::from(east));
+            assert!((projected.x.hypot(projected.y) - metres).abs() < 1e-6);
+        }
+    }
+}
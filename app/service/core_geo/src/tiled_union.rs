@@ -0,0 +1,549 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use geo::{BooleanOps, BoundingRect, Coord, Geometry, MultiPolygon, Polygon, Rect};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use tracing::{debug, instrument, warn};
+
+use crate::union::{flatten, par_union, snap, union, zero_buffer, Degraded, Fallback, Unioned};
+
+/// A unioned polygon
+#[derive(Debug)]
+pub struct Area {
+    pub polygon: Polygon<f64>,
+    /// the sorted input indexes unioned into it
+    pub sources: Vec<usize>,
+    /// whether any of the clips or unions making it needed a fallback, so it may
+    /// not be exact
+    pub degraded: bool,
+}
+
+/// A tile's unioned polygons
+struct TilePieces {
+    inside: Vec<Area>,
+    /// touching the tile's edges, so needing stitched to neighbouring tiles
+    touching: Vec<Area>,
+    degraded: Vec<Degraded>,
+}
+
+/// What `TiledUnion::union_each` found besides the dissolved areas
+#[derive(Default, Debug)]
+pub struct Dissolved {
+    pub degraded: Vec<Degraded>,
+    /// the most polygons stitched together in one go, which bounds the work done
+    /// and memory used at any one time
+    pub largest_stitch: usize,
+}
+
+/// Dissolves polygons one tile of a square grid at a time, so that a large area
+/// never has to be unioned in one go.
+///
+/// Polygons crossing tile edges are clipped into each tile they overlap. The tiles
+/// are worked through a row at a time, with the row's tiles unioned in parallel.
+/// Then the pieces touching a tile edge are stitched together with those left
+/// touching the top of the row before, and any which don't reach the top of this
+/// row are finished.
+pub struct TiledUnion {
+    polygons: Vec<Polygon<f64>>,
+    /// the index in the input of each of `polygons`
+    inputs: Vec<usize>,
+    tile_size: f64,
+    /// indexes into `polygons`, by tile, sorted by row
+    tiles: Vec<((i64, i64), Vec<usize>)>,
+}
+
+impl TiledUnion {
+    /// `tile_size` is in the same units as the coords, so degrees for OSM data, and
+    /// must be finite and greater than zero
+    #[instrument(skip(geometry))]
+    pub fn new(
+        geometry: Vec<Geometry<f64>>,
+        tile_size: f64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if !(tile_size.is_finite() && tile_size > 0.0) {
+            return Err(format!("tile size must be finite and positive, not {}", tile_size).into());
+        }
+        let (polygons, inputs) = flatten(geometry);
+
+        let mut tiles: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+        for (i, polygon) in polygons.iter().enumerate() {
+            let Some(bounds) = polygon.bounding_rect() else {
+                continue;
+            };
+            let (min_x, min_y) = tile_of(bounds.min(), tile_size);
+            let (max_x, max_y) = tile_of(bounds.max(), tile_size);
+            for x in min_x..=max_x {
+                for y in min_y..=max_y {
+                    tiles.entry((x, y)).or_default().push(i);
+                }
+            }
+        }
+        let mut tiles: Vec<_> = tiles.into_iter().collect();
+        tiles.sort_by_key(|((x, y), _)| (*y, *x));
+        debug!("{} polygons over {} tiles", polygons.len(), tiles.len());
+
+        Ok(TiledUnion {
+            polygons,
+            inputs,
+            tile_size,
+            tiles,
+        })
+    }
+
+    /// The number of tiles, which is how many times `union` will report progress
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    /// Union everything, calling `on_tile_done` as each tile is finished
+    #[instrument(skip(self, on_tile_done))]
+    pub fn union<F>(self, on_tile_done: F) -> Result<Unioned, Box<dyn std::error::Error>>
+    where
+        F: Fn() + Sync,
+    {
+        let mut unioned = Unioned::default();
+        let dissolved = self.union_each(on_tile_done, |area| {
+            unioned.geometry.push(Geometry::Polygon(area.polygon));
+            unioned.sources.push(area.sources);
+            Ok(())
+        })?;
+        unioned.degraded = dissolved.degraded;
+        Ok(unioned)
+    }
+
+    /// Union everything, handing each dissolved area to `on_area` as soon as it's
+    /// finished, so that they needn't all be held at once
+    #[instrument(skip(self, on_tile_done, on_area))]
+    pub fn union_each<F, A>(
+        self,
+        on_tile_done: F,
+        mut on_area: A,
+    ) -> Result<Dissolved, Box<dyn std::error::Error>>
+    where
+        F: Fn() + Sync,
+        A: FnMut(Area) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let mut dissolved = Dissolved::default();
+        // stitched pieces touching the top of the last row, which may carry on
+        // into the next
+        let mut open: Vec<Area> = vec![];
+        let mut last_row = None;
+        for row in self.tiles.chunk_by(|((_, a), _), ((_, b), _)| a == b) {
+            let y = row[0].0 .1;
+            if last_row.is_some_and(|last| last + 1 != y) {
+                // there's a gap between the rows, so nothing open can carry on
+                for area in open.drain(..) {
+                    on_area(area)?;
+                }
+            }
+            let unioned_tiles: Vec<TilePieces> = row
+                .par_iter()
+                .map(|(tile, ids)| {
+                    let unioned = self.union_tile(*tile, ids);
+                    on_tile_done();
+                    unioned
+                })
+                .collect::<Result<_, String>>()?;
+
+            let mut seams = std::mem::take(&mut open);
+            for tile in unioned_tiles {
+                for area in tile.inside {
+                    on_area(area)?;
+                }
+                seams.extend(tile.touching);
+                dissolved.degraded.extend(tile.degraded);
+            }
+            dissolved.largest_stitch = dissolved.largest_stitch.max(seams.len());
+            let top = (y + 1) as f64 * self.tile_size;
+            for area in stitch(seams, &mut dissolved.degraded)? {
+                if area
+                    .polygon
+                    .bounding_rect()
+                    .is_some_and(|b| b.max().y >= top)
+                {
+                    open.push(area);
+                } else {
+                    on_area(area)?;
+                }
+            }
+            last_row = Some(y);
+        }
+        for area in open {
+            on_area(area)?;
+        }
+        debug!(
+            "stitched at most {} polygons at once",
+            dissolved.largest_stitch
+        );
+        Ok(dissolved)
+    }
+
+    /// Union the parts of the polygons `ids` in `tile`, split into those inside the
+    /// tile and those touching its edges
+    fn union_tile(&self, tile: (i64, i64), ids: &[usize]) -> Result<TilePieces, String> {
+        let rect = self.rect_of(tile);
+        let clip = MultiPolygon::new(vec![rect.to_polygon()]);
+        let mut pieces: Vec<Geometry<f64>> = vec![];
+        // the input index of each of `pieces`, as a source for `sources_of`
+        let mut piece_inputs: Vec<Vec<usize>> = vec![];
+        // whether each of `pieces` came from a clip which needed a fallback
+        let mut piece_degraded: Vec<bool> = vec![];
+        let mut degraded = vec![];
+        for id in ids {
+            let polygon = &self.polygons[*id];
+            let input = self.inputs[*id];
+            if polygon.bounding_rect().is_some_and(|b| within(&b, &rect)) {
+                pieces.push(Geometry::Polygon(polygon.clone()));
+                piece_inputs.push(vec![input]);
+                piece_degraded.push(false);
+                continue;
+            }
+            let subject = MultiPolygon::new(vec![polygon.clone()]);
+            let clipped = robust_clip(&subject, &clip, |subject, clip| subject.intersection(clip));
+            let Some((clipped, fallback)) = clipped else {
+                warn!("Panic detected clipping polygon {} to tile {:?}", id, tile);
+                degraded.push(Degraded {
+                    inputs: vec![input],
+                    fallback: Fallback::Dropped,
+                });
+                continue;
+            };
+            if let Some(fallback) = fallback {
+                degraded.push(Degraded {
+                    inputs: vec![input],
+                    fallback,
+                });
+            }
+            for piece in clipped {
+                pieces.push(Geometry::Polygon(piece));
+                piece_inputs.push(vec![input]);
+                piece_degraded.push(fallback.is_some());
+            }
+        }
+
+        let unioned = union(pieces).map_err(|e| e.to_string())?;
+        let mut inside = vec![];
+        let mut touching = vec![];
+        for (i, (geometry, pieces)) in unioned.geometry.iter().zip(&unioned.sources).enumerate() {
+            let Geometry::Polygon(polygon) = geometry else {
+                continue;
+            };
+            let area = Area {
+                polygon: polygon.clone(),
+                sources: sources_of(pieces, &piece_inputs),
+                degraded: unioned.is_degraded(i) || pieces.iter().any(|p| piece_degraded[*p]),
+            };
+            if polygon
+                .bounding_rect()
+                .is_some_and(|b| strictly_within(&b, &rect))
+            {
+                inside.push(area);
+            } else {
+                touching.push(area);
+            }
+        }
+        for piece_degraded in unioned.degraded {
+            degraded.push(Degraded {
+                inputs: sources_of(&piece_degraded.inputs, &piece_inputs),
+                fallback: piece_degraded.fallback,
+            });
+        }
+        Ok(TilePieces {
+            inside,
+            touching,
+            degraded,
+        })
+    }
+
+    fn rect_of(&self, (x, y): (i64, i64)) -> Rect<f64> {
+        let min = Coord {
+            x: x as f64 * self.tile_size,
+            y: y as f64 * self.tile_size,
+        };
+        let max = Coord {
+            x: (x + 1) as f64 * self.tile_size,
+            y: (y + 1) as f64 * self.tile_size,
+        };
+        Rect::new(min, max)
+    }
+}
+
+/// Union the pieces along the seams, adding any fallbacks needed to `degraded`
+fn stitch(
+    seams: Vec<Area>,
+    degraded: &mut Vec<Degraded>,
+) -> Result<Vec<Area>, Box<dyn std::error::Error>> {
+    let mut polygons = Vec::with_capacity(seams.len());
+    let mut seam_sources = Vec::with_capacity(seams.len());
+    let mut seam_degraded = Vec::with_capacity(seams.len());
+    for area in seams {
+        polygons.push(Geometry::Polygon(area.polygon));
+        seam_sources.push(area.sources);
+        seam_degraded.push(area.degraded);
+    }
+    let stitched = par_union(polygons)?;
+    let inputs_of = |pieces: &[usize]| sources_of(pieces, &seam_sources);
+    let areas = stitched
+        .geometry
+        .iter()
+        .zip(&stitched.sources)
+        .enumerate()
+        .filter_map(|(i, (geometry, pieces))| match geometry {
+            Geometry::Polygon(polygon) => Some(Area {
+                polygon: polygon.clone(),
+                sources: inputs_of(pieces),
+                degraded: stitched.is_degraded(i) || pieces.iter().any(|p| seam_degraded[*p]),
+            }),
+            _ => None,
+        })
+        .collect();
+    for stitch_degraded in stitched.degraded {
+        degraded.push(Degraded {
+            inputs: inputs_of(&stitch_degraded.inputs),
+            fallback: stitch_degraded.fallback,
+        });
+    }
+    Ok(areas)
+}
+
+/// Clip `subject` to `clip` with `intersection`, retrying with snapped coords and
+/// then after a zero-distance buffer if it panics; `None` if they all do
+fn robust_clip<F>(
+    subject: &MultiPolygon,
+    clip: &MultiPolygon,
+    intersection: F,
+) -> Option<(MultiPolygon, Option<Fallback>)>
+where
+    F: Fn(&MultiPolygon, &MultiPolygon) -> MultiPolygon,
+{
+    let attempt = |op: &dyn Fn() -> MultiPolygon| panic::catch_unwind(AssertUnwindSafe(op)).ok();
+
+    if let Some(clipped) = attempt(&|| intersection(subject, clip)) {
+        return Some((clipped, None));
+    }
+    warn!("Panic detected in clip, retrying with snapped coords");
+    if let Some(clipped) = attempt(&|| intersection(&snap(subject), &snap(clip))) {
+        return Some((clipped, Some(Fallback::Snapped)));
+    }
+    warn!("Panic detected in snapped clip, retrying after zero-distance buffer");
+    attempt(&|| intersection(&zero_buffer(subject), clip))
+        .map(|clipped| (clipped, Some(Fallback::Buffered)))
+}
+
+/// The sorted input indexes of `pieces`, given the inputs of each piece
+fn sources_of(pieces: &[usize], inputs_of_pieces: &[Vec<usize>]) -> Vec<usize> {
+    let mut sources: Vec<usize> = pieces
+        .iter()
+        .flat_map(|piece| inputs_of_pieces[*piece].iter().copied())
+        .collect();
+    sources.sort_unstable();
+    sources.dedup();
+    sources
+}
+
+fn tile_of(coord: Coord<f64>, tile_size: f64) -> (i64, i64) {
+    (
+        (coord.x / tile_size).floor() as i64,
+        (coord.y / tile_size).floor() as i64,
+    )
+}
+
+fn within(inner: &Rect<f64>, outer: &Rect<f64>) -> bool {
+    inner.min().x >= outer.min().x
+        && inner.min().y >= outer.min().y
+        && inner.max().x <= outer.max().x
+        && inner.max().y <= outer.max().y
+}
+
+fn strictly_within(inner: &Rect<f64>, outer: &Rect<f64>) -> bool {
+    inner.min().x > outer.min().x
+        && inner.min().y > outer.min().y
+        && inner.max().x < outer.max().x
+        && inner.max().y < outer.max().y
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use geo::Area as _;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn square(x: f64, y: f64, size: f64) -> Geometry<f64> {
+        Geometry::Polygon(Polygon::new(
+            vec![
+                (x, y),
+                (x + size, y),
+                (x + size, y + size),
+                (x, y + size),
+                (x, y),
+            ]
+            .into(),
+            vec![],
+        ))
+    }
+
+    fn polygon_of(geometry: Geometry<f64>) -> Polygon<f64> {
+        let Geometry::Polygon(polygon) = geometry else {
+            panic!("expected a Polygon, got {:?}", geometry);
+        };
+        polygon
+    }
+
+    fn total_area(geometry: &[Geometry<f64>]) -> f64 {
+        geometry.iter().map(|g| g.unsigned_area()).sum()
+    }
+
+    /// a row of overlapping squares crossing several tiles, and one square on its own
+    fn squares() -> Vec<Geometry<f64>> {
+        let mut squares: Vec<Geometry<f64>> = (0..10)
+            .map(|i| square(i as f64 * 0.75, 0.25, 1.0))
+            .collect();
+        squares.push(square(20.25, 20.25, 0.5));
+        squares
+    }
+
+    #[test]
+    fn tiled_union_matches_union() {
+        let expected = union(squares()).unwrap().geometry;
+        let tiled = TiledUnion::new(squares(), 2.0).unwrap();
+        let done = AtomicUsize::new(0);
+        let tiles = tiled.len();
+        let actual = tiled
+            .union(|| {
+                done.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+
+        assert_eq!(actual.geometry.len(), expected.len());
+        assert!((total_area(&actual.geometry) - total_area(&expected)).abs() < 1e-9);
+        // the squares are all one area, bar the last one
+        let mut sources = actual.sources.clone();
+        sources.sort();
+        assert_eq!(sources, vec![(0..10).collect::<Vec<_>>(), vec![10]]);
+        assert_eq!(done.into_inner(), tiles);
+    }
+
+    #[test]
+    fn tile_sizes_must_be_finite_and_positive() {
+        for tile_size in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(
+                TiledUnion::new(squares(), tile_size).is_err(),
+                "{}",
+                tile_size
+            );
+        }
+    }
+
+    #[test]
+    fn seams_are_stitched_a_row_at_a_time() {
+        // one square over a 20 x 20 grid of tiles, so every tile's piece is on a seam
+        let tiled = TiledUnion::new(vec![square(0.25, 0.25, 19.5)], 1.0).unwrap();
+        assert_eq!(tiled.len(), 400);
+        let mut areas = vec![];
+        let dissolved = tiled
+            .union_each(
+                || {},
+                |area| {
+                    areas.push(area);
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        assert_eq!(areas.len(), 1);
+        assert!((areas[0].polygon.unsigned_area() - 19.5 * 19.5).abs() < 1e-9);
+        assert_eq!(areas[0].sources, vec![0]);
+        assert!(!areas[0].degraded);
+        // a row of pieces, and what's been stitched together below them
+        assert_eq!(dissolved.largest_stitch, 20 + 1);
+    }
+
+    #[test]
+    fn areas_are_handed_over_once_finished() {
+        // squares over 3 x 3 tiles each, at the bottom and top of the grid
+        let tiled =
+            TiledUnion::new(vec![square(0.5, 0.5, 2.0), square(0.5, 10.5, 2.0)], 1.0).unwrap();
+        assert_eq!(tiled.len(), 18);
+        let done = AtomicUsize::new(0);
+        let mut handed_over = vec![];
+        tiled
+            .union_each(
+                || {
+                    done.fetch_add(1, Ordering::Relaxed);
+                },
+                |area| {
+                    handed_over.push((area.sources, done.load(Ordering::Relaxed)));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(handed_over, vec![(vec![0], 9), (vec![1], 18)]);
+    }
+
+    #[test]
+    fn clips_fall_back_in_order() {
+        let subject = MultiPolygon::new(vec![polygon_of(square(0.5, 0.5, 2.0))]);
+        let clip = MultiPolygon::new(vec![polygon_of(square(0.0, 0.0, 1.0))]);
+        for (panics, expected) in [
+            (0, Some(None)),
+            (1, Some(Some(Fallback::Snapped))),
+            (2, Some(Some(Fallback::Buffered))),
+            (3, None),
+        ] {
+            let calls = Cell::new(0);
+            let clipped = robust_clip(&subject, &clip, |subject, clip| {
+                calls.set(calls.get() + 1);
+                if calls.get() <= panics {
+                    panic!("clip failed");
+                }
+                subject.intersection(clip)
+            });
+            assert_eq!(clipped.as_ref().map(|(_, fallback)| *fallback), expected);
+            if let Some((clipped, _)) = clipped {
+                assert!((clipped.unsigned_area() - 0.25).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn stitched_areas_stay_degraded() {
+        let area = |geometry, sources, degraded| Area {
+            polygon: polygon_of(geometry),
+            sources,
+            degraded,
+        };
+        let seams = vec![
+            area(square(0.0, 0.0, 1.0), vec![0], true),
+            area(square(0.0, 1.0, 1.0), vec![1], false),
+            area(square(5.0, 5.0, 1.0), vec![2], false),
+        ];
+        let mut stitched = stitch(seams, &mut vec![]).unwrap();
+        stitched.sort_by(|a, b| a.sources.cmp(&b.sources));
+        let degraded: Vec<_> = stitched
+            .iter()
+            .map(|area| (area.sources.clone(), area.degraded))
+            .collect();
+        assert_eq!(degraded, vec![(vec![0, 1], true), (vec![2], false)]);
+    }
+
+    #[test]
+    fn polygons_inside_a_tile_are_not_stitched() {
+        let tiled =
+            TiledUnion::new(vec![square(0.25, 0.25, 0.5), square(0.5, 0.5, 0.25)], 2.0).unwrap();
+        assert_eq!(tiled.len(), 1);
+        let actual = tiled.union(|| {}).unwrap();
+        assert_eq!(actual.geometry.len(), 1);
+        assert_eq!(total_area(&actual.geometry), 0.25);
+        assert_eq!(actual.sources, vec![vec![0, 1]]);
+    }
+}
@@ -1,6 +1,16 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    panic::{self, AssertUnwindSafe},
+};
+
+use cavalier_contours::polyline::{BooleanOp, PlineBooleanOptions, PlineSource, Polyline};
+
+use crate::{buffer::buffer_polygon, cavalier};
+use geo::{
+    BooleanOps, Coord, Geometry, Intersects, MapCoords, MultiPolygon, Polygon, RemoveRepeatedPoints,
+};
+use rayon::prelude::*;
 
-use geo::{BooleanOps, Geometry, Intersects, MultiPolygon, Polygon};
 use rstar::{
     primitives::{CachedEnvelope, GeomWithData},
     RTree,
@@ -15,6 +25,7 @@ struct Partitioned {
     disjunctive_groups: Vec<HashSet<PolygonId>>,
 }
 
+#[cfg(any(test, feature = "bench"))]
 #[derive(Clone, Copy, PartialEq, Debug)]
 struct GroupId(usize);
 
@@ -42,8 +53,93 @@ fn intersection_candidates(polygons: &[Polygon]) -> Vec<(PolygonId, PolygonId)>
     candidates
 }
 
+/// Disjoint-set forest over polygon indexes, with path compression and union by rank
+struct DisjointSet {
+    parents: Vec<usize>,
+    ranks: Vec<u8>,
+}
+
+impl DisjointSet {
+    fn new(len: usize) -> Self {
+        DisjointSet {
+            parents: (0..len).collect(),
+            ranks: vec![0; len],
+        }
+    }
+
+    fn find(&mut self, id: usize) -> usize {
+        let mut root = id;
+        while self.parents[root] != root {
+            root = self.parents[root];
+        }
+        // point everything on the path straight at the root
+        let mut id = id;
+        while self.parents[id] != root {
+            let parent = self.parents[id];
+            self.parents[id] = root;
+            id = parent;
+        }
+        root
+    }
+
+    /// Returns false if `a` and `b` were already in the same set
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        let (child, parent) = if self.ranks[a] < self.ranks[b] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        self.parents[child] = parent;
+        if self.ranks[child] == self.ranks[parent] {
+            self.ranks[parent] += 1;
+        }
+        true
+    }
+}
+
 #[instrument(skip(polygons))]
 fn partition(polygons: &[Polygon]) -> Partitioned {
+    let mut sets = DisjointSet::new(polygons.len());
+
+    let candidates = intersection_candidates(polygons);
+
+    debug!("Finding disjunctive groups");
+    for (p1_id, p2_id) in candidates {
+        // don't bother checking for intersection if already in the same group
+        if sets.find(p1_id.0) == sets.find(p2_id.0) {
+            continue;
+        }
+        if polygons[p1_id.0].intersects(&polygons[p2_id.0]) {
+            sets.union(p1_id.0, p2_id.0);
+        }
+    }
+
+    let mut groups_by_root: Vec<Option<usize>> = vec![None; polygons.len()];
+    let mut disjunctive_groups: Vec<HashSet<PolygonId>> = vec![];
+    for id in 0..polygons.len() {
+        let root = sets.find(id);
+        let group = *groups_by_root[root].get_or_insert_with(|| {
+            disjunctive_groups.push(HashSet::new());
+            disjunctive_groups.len() - 1
+        });
+        disjunctive_groups[group].insert(PolygonId(id));
+    }
+    debug!("Found {} disjunctive groups", disjunctive_groups.len());
+
+    trace!("disjunctive_groups: {:?}", disjunctive_groups);
+
+    Partitioned { disjunctive_groups }
+}
+
+/// The original partitioning, which clones and reassigns a whole group on every
+/// merge, so is quadratic for large connected clusters. Kept to benchmark against.
+#[cfg(any(test, feature = "bench"))]
+#[instrument(skip(polygons))]
+fn partition_by_merging(polygons: &[Polygon]) -> Partitioned {
     let polygon_ids = polygons
         .iter()
         .enumerate()
@@ -100,19 +196,109 @@ fn partition(polygons: &[Polygon]) -> Partitioned {
     Partitioned { disjunctive_groups }
 }
 
+/// OSM's coordinate precision, in degrees, which coords are snapped to when a
+/// union fails
+const SNAP_GRID: f64 = 1e-7;
+
+/// How a union which made geo's `BooleanOps` panic was done instead, from most to
+/// least exact
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Fallback {
+    /// with coords snapped to OSM's precision
+    Snapped,
+    /// after cleaning both sides with a zero-distance buffer, which can drop
+    /// self-intersecting parts
+    Buffered,
+    /// with cavalier_contours, which fills in holes overlapped by the other side
+    Cavalier,
+    /// not unioned at all, so the result has overlapping polygons
+    Unmerged,
+    /// part of an input couldn't be clipped to a tile at all, so was left out
+    /// rather than overlap the tile's other parts
+    Dropped,
+}
+
+/// A union which needed a `Fallback`, so may not be exact
+#[derive(Clone, PartialEq, Debug)]
+pub struct Degraded {
+    /// indexes of the input geometries involved
+    pub inputs: Vec<usize>,
+    pub fallback: Fallback,
+}
+
+#[derive(Default, Debug)]
+pub struct Unioned {
+    pub geometry: Vec<Geometry<f64>>,
+    /// for each of `geometry`, the sorted indexes of the input geometries unioned
+    /// into it
+    pub sources: Vec<Vec<usize>>,
+    pub degraded: Vec<Degraded>,
+}
+
+impl Unioned {
+    /// Whether `geometry[index]` came from a union which needed a fallback
+    pub fn is_degraded(&self, index: usize) -> bool {
+        let sources = &self.sources[index];
+        self.degraded.iter().any(|degraded| {
+            degraded
+                .inputs
+                .iter()
+                .any(|input| sources.binary_search(input).is_ok())
+        })
+    }
+}
+
+/// The polygons in all the areal `geometry`, with the index in `geometry` each
+/// came from. Points and lines have no area, so are left out.
+pub fn flatten(geometry: Vec<Geometry<f64>>) -> (Vec<Polygon<f64>>, Vec<usize>) {
+    fn polygons_of(geometry: Geometry<f64>, polygons: &mut Vec<Polygon<f64>>) {
+        match geometry {
+            Geometry::Polygon(polygon) => polygons.push(polygon),
+            Geometry::MultiPolygon(multi_polygon) => polygons.extend(multi_polygon),
+            Geometry::Rect(rect) => polygons.push(rect.to_polygon()),
+            Geometry::Triangle(triangle) => polygons.push(triangle.to_polygon()),
+            Geometry::GeometryCollection(collection) => {
+                for geometry in collection {
+                    polygons_of(geometry, polygons);
+                }
+            }
+            Geometry::Point(_)
+            | Geometry::Line(_)
+            | Geometry::LineString(_)
+            | Geometry::MultiPoint(_)
+            | Geometry::MultiLineString(_) => {}
+        }
+    }
+
+    let mut polygons = vec![];
+    let mut inputs = vec![];
+    for (i, geometry) in geometry.into_iter().enumerate() {
+        polygons_of(geometry, &mut polygons);
+        inputs.resize(polygons.len(), i);
+    }
+    (polygons, inputs)
+}
+
+/// Union all the areal geometry, so that none of the output overlaps. Polygons
+/// are unioned with those they intersect, and the rest passed through.
+#[instrument(skip(geometry))]
+pub fn union(geometry: Vec<Geometry<f64>>) -> Result<Unioned, Box<dyn std::error::Error>> {
+    union_groups(geometry, false)
+}
+
+/// As `union`, but with the disjunctive groups unioned in parallel
 #[instrument(skip(geometry))]
-pub fn union(
+pub fn par_union(geometry: Vec<Geometry<f64>>) -> Result<Unioned, Box<dyn std::error::Error>> {
+    union_groups(geometry, true)
+}
+
+fn union_groups(
     geometry: Vec<Geometry<f64>>,
-) -> Result<Vec<Geometry<f64>>, Box<dyn std::error::Error>> {
-    let polygons = geometry
-        .iter()
-        .filter_map(|g| match g {
-            Geometry::Polygon(p) => Some(p.clone()),
-            _ => None,
-        })
-        .collect::<Vec<_>>();
+    parallel: bool,
+) -> Result<Unioned, Box<dyn std::error::Error>> {
+    let (polygons, inputs) = flatten(geometry);
     if polygons.is_empty() {
-        return Err("No polygons found".into());
+        return Ok(Unioned::default());
     };
 
     let partitioned = partition(&polygons);
@@ -123,58 +309,215 @@ pub fn union(
     );
 
     debug!("Unioning polygons");
-    let mut unioned_polygons: Vec<Polygon<f64>> = vec![];
-    for group in partitioned.disjunctive_groups {
-        if group.is_empty() {
-            continue;
+    let union_group = |group: HashSet<PolygonId>| {
+        // in input order, so that neighbouring inputs tend to be unioned first
+        let mut ids: Vec<PolygonId> = group.into_iter().collect();
+        ids.sort_by_key(|id| id.0);
+        let mut sources: Vec<usize> = ids.iter().map(|id| inputs[id.0]).collect();
+        sources.dedup();
+        let mut degraded = vec![];
+        if ids.len() == 1 {
+            return (vec![polygons[ids[0].0].clone()], sources, degraded);
         }
-        if group.len() == 1 {
-            let id = group.iter().next().unwrap().0;
-            unioned_polygons.push(polygons[id].clone());
-        } else {
-            let multi: Vec<MultiPolygon> = group
-                .into_iter()
-                .map(|p| MultiPolygon::new(vec![polygons[p.0].clone()]))
-                .collect();
+        let parts: Vec<(MultiPolygon, Vec<usize>)> = ids
+            .into_iter()
+            .map(|id| {
+                (
+                    MultiPolygon::new(vec![polygons[id.0].clone()]),
+                    vec![inputs[id.0]],
+                )
+            })
+            .collect();
+        let unioned = cascaded_union(parts, &mut degraded);
+        (unioned.0, sources, degraded)
+    };
+    let groups = partitioned
+        .disjunctive_groups
+        .into_iter()
+        .filter(|group| !group.is_empty());
+    let unioned_groups: Vec<_> = if parallel {
+        groups
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(union_group)
+            .collect()
+    } else {
+        groups.map(union_group).collect()
+    };
 
-            let unioned = multi
-                .iter()
-                .skip(1)
-                .fold(multi[0].clone(), panic_safe_union);
+    let mut unioned = Unioned::default();
+    for (polygons, sources, degraded) in unioned_groups {
+        for polygon in polygons {
+            unioned.geometry.push(Geometry::Polygon(polygon));
+            unioned.sources.push(sources.clone());
+        }
+        unioned.degraded.extend(degraded);
+    }
+    debug!("Unioned {} polygons", unioned.geometry.len());
+    if !unioned.degraded.is_empty() {
+        warn!("{} unions needed a fallback", unioned.degraded.len());
+    }
+
+    Ok(unioned)
+}
 
-            unioned_polygons.append(unioned.into_iter().collect::<Vec<Polygon<f64>>>().as_mut());
+/// Union `parts` pairwise, a round at a time, so that each union is between
+/// similarly sized inputs rather than into one ever-growing accumulator. Each part
+/// comes with the input indexes in it, which are added to `degraded` when a union
+/// needs a fallback.
+fn cascaded_union(
+    mut parts: Vec<(MultiPolygon, Vec<usize>)>,
+    degraded: &mut Vec<Degraded>,
+) -> MultiPolygon {
+    while parts.len() > 1 {
+        trace!("cascading {} parts", parts.len());
+        let mut next = Vec::with_capacity(parts.len().div_ceil(2));
+        let mut remaining = parts.into_iter();
+        while let Some((lhs, mut inputs)) = remaining.next() {
+            next.push(match remaining.next() {
+                Some((rhs, rhs_inputs)) => {
+                    let (unioned, fallback) = robust_union(lhs, &rhs);
+                    // both sorted, and all of `inputs` before `rhs_inputs`
+                    inputs.extend(rhs_inputs);
+                    inputs.dedup();
+                    if let Some(fallback) = fallback {
+                        degraded.push(Degraded {
+                            inputs: inputs.clone(),
+                            fallback,
+                        });
+                    }
+                    (unioned, inputs)
+                }
+                None => (lhs, inputs),
+            });
         }
+        parts = next;
     }
-    debug!("Unioned {} polygons", unioned_polygons.len());
+    parts
+        .pop()
+        .map_or_else(|| MultiPolygon::new(vec![]), |(unioned, _)| unioned)
+}
 
-    debug!("converting to Geometry");
-    let unioned = unioned_polygons
-        .into_iter()
-        .map(Geometry::Polygon)
-        .collect();
-    debug!("converted to Geometry");
+/// Union with geo, working through each `Fallback` in turn if it panics
+fn robust_union(lhs: MultiPolygon, rhs: &MultiPolygon) -> (MultiPolygon, Option<Fallback>) {
+    robust_union_with(lhs, rhs, |lhs, rhs| lhs.union(rhs))
+}
 
-    Ok(unioned)
+fn robust_union_with<F>(
+    lhs: MultiPolygon,
+    rhs: &MultiPolygon,
+    union: F,
+) -> (MultiPolygon, Option<Fallback>)
+where
+    F: Fn(&MultiPolygon, &MultiPolygon) -> MultiPolygon,
+{
+    let attempt = |op: &dyn Fn() -> MultiPolygon| panic::catch_unwind(AssertUnwindSafe(op)).ok();
+
+    if let Some(unioned) = attempt(&|| union(&lhs, rhs)) {
+        return (unioned, None);
+    }
+    warn!("Panic detected in union, retrying with snapped coords");
+    if let Some(unioned) = attempt(&|| union(&snap(&lhs), &snap(rhs))) {
+        return (unioned, Some(Fallback::Snapped));
+    }
+    warn!("Panic detected in snapped union, retrying after zero-distance buffer");
+    if let Some(unioned) = attempt(&|| union(&zero_buffer(&lhs), &zero_buffer(rhs))) {
+        return (unioned, Some(Fallback::Buffered));
+    }
+    warn!("Panic detected in buffered union, retrying with cavalier_contours");
+    if let Some(unioned) = attempt(&|| cavalier_union(&lhs, rhs)) {
+        return (unioned, Some(Fallback::Cavalier));
+    }
+    warn!("Panic detected in cavalier_contours union, keeping both sides");
+    let mut unmerged = lhs;
+    unmerged.0.extend(rhs.iter().cloned());
+    (unmerged, Some(Fallback::Unmerged))
 }
 
-fn panic_safe_union(lhs: MultiPolygon, rhs: &MultiPolygon) -> MultiPolygon {
-    use std::panic;
+pub(crate) fn snap(multi: &MultiPolygon) -> MultiPolygon {
+    multi
+        .map_coords(|c| Coord {
+            x: (c.x / SNAP_GRID).round() * SNAP_GRID,
+            y: (c.y / SNAP_GRID).round() * SNAP_GRID,
+        })
+        .remove_repeated_points()
+}
 
-    let result = panic::catch_unwind(|| lhs.union(rhs));
+/// Each polygon buffered by nothing, which drops any self-intersecting parts
+pub(crate) fn zero_buffer(multi: &MultiPolygon) -> MultiPolygon {
+    MultiPolygon::new(
+        multi
+            .iter()
+            .flat_map(|polygon| buffer_polygon(polygon, 0.0))
+            .collect(),
+    )
+}
 
-    match result {
-        Ok(unioned) => unioned,
-        Err(_) => {
-            warn!("Panic detected in union, falling back to lhs of attempted union");
-            lhs
+/// Merge the outer rings with cavalier_contours, keeping any holes which the
+/// union encloses, and the holes of each side which the other side doesn't touch
+fn cavalier_union(lhs: &MultiPolygon, rhs: &MultiPolygon) -> MultiPolygon {
+    let options = PlineBooleanOptions {
+        pos_equal_eps: cavalier::EPS,
+        ..PlineBooleanOptions::new()
+    };
+    let mut exteriors: Vec<Polyline<f64>> = vec![];
+    let mut holes: Vec<Polyline<f64>> = vec![];
+    for polygon in lhs.iter().chain(rhs.iter()) {
+        let mut exterior = cavalier::polyline(polygon.exterior(), true);
+        let mut i = 0;
+        while i < exteriors.len() {
+            let merged = exterior.boolean_opt(&exteriors[i], BooleanOp::Or, &options);
+            if merged.pos_plines.len() == 1 {
+                exterior = merged.pos_plines.into_iter().next().unwrap().pline;
+                holes.extend(merged.neg_plines.into_iter().map(|hole| hole.pline));
+                exteriors.swap_remove(i);
+                // the merged ring may now overlap ones already passed
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        exteriors.push(exterior);
+    }
+    let sides = lhs
+        .iter()
+        .map(|p| (p, rhs))
+        .chain(rhs.iter().map(|p| (p, lhs)));
+    for (polygon, other) in sides {
+        for hole in polygon.interiors() {
+            if !other.intersects(&Polygon::new(hole.clone(), vec![])) {
+                holes.push(cavalier::polyline(hole, false));
+            }
         }
     }
+    MultiPolygon::new(cavalier::polygons(&exteriors, &holes))
+}
+
+/// Partitioning entry points for `benches/union.rs`, returning the number of
+/// non-empty groups found
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    use geo::Polygon;
+
+    pub fn partition(polygons: &[Polygon]) -> usize {
+        super::partition(polygons).disjunctive_groups.len()
+    }
+
+    pub fn partition_by_merging(polygons: &[Polygon]) -> usize {
+        super::partition_by_merging(polygons)
+            .disjunctive_groups
+            .iter()
+            .filter(|group| !group.is_empty())
+            .count()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use geo::Area;
     use pretty_assertions::assert_eq;
-    use std::{collections::HashSet, hash::Hash};
+    use std::{cell::Cell, collections::HashSet, hash::Hash};
 
     use super::*;
 
@@ -209,7 +552,7 @@ mod tests {
         sorted
     }
 
-    fn pretty_print_edgeset(edgeset: &Vec<Edge>) -> String {
+    fn pretty_print_edgeset(edgeset: &[Edge]) -> String {
         let edges: Vec<String> = edgeset
             .iter()
             .map(|e| format!("{}->{}", e.from, e.to))
@@ -242,8 +585,8 @@ mod tests {
             .into(),
             vec![],
         ));
-        let actual = union(vec![p1, p2]).unwrap();
-        let expected = vec![expected_p];
+        let actual = union(vec![p1, p2]).unwrap().geometry;
+        let expected = [expected_p];
         assert_eq!(actual.len(), 1);
         assert_equivalent_polygons(polygon(&actual[0]).unwrap(), polygon(&expected[0]).unwrap());
     }
@@ -258,8 +601,8 @@ mod tests {
             vec![(0.5, 0.5), (3.0, 0.5), (3.0, 3.0), (0.5, 3.0), (0.5, 0.5)].into(),
             vec![],
         ));
-        let actual = union(vec![outer.clone(), p1]).unwrap();
-        let expected = vec![outer];
+        let actual = union(vec![outer.clone(), p1]).unwrap().geometry;
+        let expected = [outer];
         assert_eq!(actual.len(), 1);
         assert_equivalent_polygons(polygon(&actual[0]).unwrap(), polygon(&expected[0]).unwrap());
     }
@@ -278,8 +621,8 @@ mod tests {
             vec![(0.5, 0.5), (3.0, 0.5), (3.0, 3.0), (0.5, 3.0), (0.5, 0.5)].into(),
             vec![],
         ));
-        let actual = union(vec![outer.clone(), p1, p2]).unwrap();
-        let expected = vec![outer];
+        let actual = union(vec![outer.clone(), p1, p2]).unwrap().geometry;
+        let expected = [outer];
         for a in &actual {
             println!(
                 "{:?}",
@@ -290,12 +633,311 @@ mod tests {
         assert_equivalent_polygons(polygon(&actual[0]).unwrap(), polygon(&expected[0]).unwrap());
     }
 
+    #[test]
+    fn union_includes_the_parts_of_multipolygons() {
+        let square = |x: f64| {
+            Polygon::new(
+                vec![(x, 0.0), (x + 1.0, 0.0), (x + 1.0, 1.0), (x, 1.0), (x, 0.0)].into(),
+                vec![],
+            )
+        };
+        let relation = Geometry::MultiPolygon(MultiPolygon::new(vec![square(0.0), square(5.0)]));
+        let way = Geometry::Polygon(square(0.5));
+        let actual = union(vec![relation, way]).unwrap();
+        assert_eq!(actual.geometry.len(), 2);
+        assert_eq!(actual.sources, vec![vec![0, 1], vec![0]]);
+    }
+
     fn assert_equivalent_polygons(actual: &Polygon<f64>, expected: &Polygon<f64>) {
         let actual_edges = pretty_print_edgeset(&as_edgeset(actual));
         let expected_edges = pretty_print_edgeset(&as_edgeset(expected));
         assert_eq!(actual_edges, expected_edges);
     }
 
+    fn sorted_groups(partitioned: Partitioned) -> Vec<Vec<usize>> {
+        let mut groups: Vec<Vec<usize>> = partitioned
+            .disjunctive_groups
+            .into_iter()
+            .filter(|group| !group.is_empty())
+            .map(|group| {
+                let mut ids: Vec<usize> = group.into_iter().map(|id| id.0).collect();
+                ids.sort();
+                ids
+            })
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    #[test]
+    fn partition_matches_partition_by_merging() {
+        let square = |x: f64, y: f64| {
+            Polygon::new(
+                vec![
+                    (x, y),
+                    (x + 1.0, y),
+                    (x + 1.0, y + 1.0),
+                    (x, y + 1.0),
+                    (x, y),
+                ]
+                .into(),
+                vec![],
+            )
+        };
+        // two overlapping chains, joined in the middle, plus some loners
+        let polygons = vec![
+            square(0.0, 0.0),
+            square(10.0, 0.0),
+            square(0.5, 0.5),
+            square(20.0, 20.0),
+            square(1.0, 1.0),
+            square(10.5, 0.0),
+            square(1.5, 0.0),
+            square(11.0, 0.5),
+            square(2.0, 0.0),
+        ];
+        let expected = vec![vec![0, 2, 4, 6, 8], vec![1, 5, 7], vec![3]];
+        assert_eq!(sorted_groups(partition(&polygons)), expected);
+        assert_eq!(sorted_groups(partition_by_merging(&polygons)), expected);
+    }
+
+    #[test]
+    fn disjoint_set_compresses_paths() {
+        let mut sets = DisjointSet::new(5);
+        assert!(sets.union(0, 1));
+        assert!(sets.union(2, 3));
+        assert!(sets.union(1, 3));
+        assert!(!sets.union(0, 2));
+        let root = sets.find(3);
+        assert!((0..4).all(|id| sets.find(id) == root));
+        assert!((0..4).all(|id| sets.parents[id] == root));
+        assert_eq!(sets.find(4), 4);
+    }
+
+    /// the original left fold over a group, to check `cascaded_union` against
+    fn folded_union(multi: Vec<MultiPolygon>) -> MultiPolygon {
+        multi
+            .iter()
+            .skip(1)
+            .fold(multi[0].clone(), |lhs, rhs| robust_union(lhs, rhs).0)
+    }
+
+    fn sorted_edgesets(multi: MultiPolygon) -> Vec<String> {
+        let mut edgesets: Vec<String> = multi
+            .iter()
+            .map(|p| pretty_print_edgeset(&as_edgeset(p)))
+            .collect();
+        edgesets.sort();
+        edgesets
+    }
+
+    #[test]
+    fn cascaded_union_matches_folded_union() {
+        // a staircase of overlapping squares, plus some inside others and some apart
+        let mut squares: Vec<MultiPolygon> = (0..13)
+            .map(|i| {
+                let (x, y) = (i as f64 * 0.5, i as f64 * 0.25);
+                MultiPolygon::new(vec![Polygon::new(
+                    vec![
+                        (x, y),
+                        (x + 1.0, y),
+                        (x + 1.0, y + 1.0),
+                        (x, y + 1.0),
+                        (x, y),
+                    ]
+                    .into(),
+                    vec![],
+                )])
+            })
+            .collect();
+        squares.push(MultiPolygon::new(vec![Polygon::new(
+            vec![
+                (0.25, 0.25),
+                (0.5, 0.25),
+                (0.5, 0.5),
+                (0.25, 0.5),
+                (0.25, 0.25),
+            ]
+            .into(),
+            vec![],
+        )]));
+        squares.push(MultiPolygon::new(vec![Polygon::new(
+            vec![
+                (20.0, 0.0),
+                (21.0, 0.0),
+                (21.0, 1.0),
+                (20.0, 1.0),
+                (20.0, 0.0),
+            ]
+            .into(),
+            vec![],
+        )]));
+
+        for len in 1..=squares.len() {
+            let parts = squares[..len].to_vec();
+            assert_eq!(
+                sorted_edgesets(cascaded_union(
+                    parts.iter().cloned().map(|p| (p, vec![])).collect(),
+                    &mut vec![]
+                )),
+                sorted_edgesets(folded_union(parts)),
+                "first {} squares",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn par_union_matches_union() {
+        let squares: Vec<Geometry<f64>> = (0..20)
+            .map(|i| {
+                // pairs of overlapping squares, each pair apart from the others
+                let (x, y) = ((i / 2) as f64 * 3.0 + (i % 2) as f64 * 0.5, 0.0);
+                Geometry::Polygon(Polygon::new(
+                    vec![
+                        (x, y),
+                        (x + 1.0, y),
+                        (x + 1.0, y + 1.0),
+                        (x, y + 1.0),
+                        (x, y),
+                    ]
+                    .into(),
+                    vec![],
+                ))
+            })
+            .collect();
+        let edgesets = |geometry: Vec<Geometry<f64>>| {
+            sorted_edgesets(MultiPolygon::new(
+                geometry.iter().filter_map(polygon).cloned().collect(),
+            ))
+        };
+        let expected = edgesets(union(squares.clone()).unwrap().geometry);
+        assert_eq!(expected.len(), 10);
+        assert_eq!(edgesets(par_union(squares).unwrap().geometry), expected);
+    }
+
+    fn square(x: f64, y: f64, size: f64) -> Polygon<f64> {
+        Polygon::new(
+            vec![
+                (x, y),
+                (x + size, y),
+                (x + size, y + size),
+                (x, y + size),
+                (x, y),
+            ]
+            .into(),
+            vec![],
+        )
+    }
+
+    #[test]
+    fn union_falls_back_in_order() {
+        let lhs = MultiPolygon::new(vec![square(0.0, 0.0, 2.0)]);
+        let rhs = MultiPolygon::new(vec![square(1.0, 1.0, 2.0)]);
+        for (panics, expected) in [
+            (0, None),
+            (1, Some(Fallback::Snapped)),
+            (2, Some(Fallback::Buffered)),
+            (3, Some(Fallback::Cavalier)),
+        ] {
+            let calls = Cell::new(0);
+            let (unioned, fallback) = robust_union_with(lhs.clone(), &rhs, |lhs, rhs| {
+                calls.set(calls.get() + 1);
+                if calls.get() <= panics {
+                    panic!("union failed");
+                }
+                lhs.union(rhs)
+            });
+            assert_eq!(fallback, expected);
+            assert_eq!(unioned.0.len(), 1);
+            assert!((unioned.unsigned_area() - 7.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn cavalier_union_keeps_untouched_holes() {
+        let with_hole = Polygon::new(
+            square(0.0, 0.0, 4.0).exterior().clone(),
+            vec![square(1.0, 1.0, 1.0).exterior().clone()],
+        );
+        let lhs = MultiPolygon::new(vec![with_hole]);
+
+        let apart = MultiPolygon::new(vec![square(3.0, 3.0, 2.0), square(10.0, 10.0, 1.0)]);
+        let unioned = cavalier_union(&lhs, &apart);
+        assert_eq!(unioned.0.len(), 2);
+        assert!((unioned.unsigned_area() - (16.0 - 1.0 + 4.0 - 1.0 + 1.0)).abs() < 1e-9);
+
+        // a polygon in the hole means it's filled in, which is where this is inexact
+        let in_hole = MultiPolygon::new(vec![square(1.25, 1.25, 0.5)]);
+        let unioned = cavalier_union(&lhs, &in_hole);
+        assert_eq!(unioned.0.len(), 1);
+        assert!((unioned.unsigned_area() - 16.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn degraded_outputs_are_found_by_their_sources() {
+        let unioned = Unioned {
+            geometry: vec![square(0.0, 0.0, 1.0).into(), square(5.0, 5.0, 1.0).into()],
+            sources: vec![vec![0, 2], vec![1]],
+            degraded: vec![Degraded {
+                inputs: vec![2],
+                fallback: Fallback::Snapped,
+            }],
+        };
+        assert!(unioned.is_degraded(0));
+        assert!(!unioned.is_degraded(1));
+    }
+
+    #[test]
+    fn union_records_sources() {
+        let unioned = union(vec![
+            square(0.0, 0.0, 2.0).into(),
+            square(5.0, 5.0, 1.0).into(),
+            square(1.0, 1.0, 2.0).into(),
+        ])
+        .unwrap();
+        let mut sources = unioned.sources.clone();
+        sources.sort();
+        assert_eq!(sources, vec![vec![0, 2], vec![1]]);
+        assert!(unioned.degraded.is_empty());
+    }
+
+    #[test]
+    fn union_flattens_areal_geometry() {
+        let geometry = vec![
+            Geometry::MultiPolygon(MultiPolygon::new(vec![
+                square(0.0, 0.0, 2.0),
+                square(10.0, 10.0, 1.0),
+            ])),
+            Geometry::Point((20.0, 20.0).into()),
+            Geometry::GeometryCollection(geo::GeometryCollection::new_from(vec![
+                square(1.0, 1.0, 2.0).into(),
+                Geometry::LineString(vec![(0.0, 0.0), (5.0, 5.0)].into()),
+                Geometry::Rect(geo::Rect::new((30.0, 30.0), (31.0, 31.0))),
+            ])),
+        ];
+        let unioned = union(geometry).unwrap();
+        let mut areas: Vec<(Vec<usize>, f64)> = unioned
+            .sources
+            .iter()
+            .cloned()
+            .zip(unioned.geometry.iter().map(|g| g.unsigned_area()))
+            .collect();
+        areas.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            areas,
+            vec![(vec![0], 1.0), (vec![0, 2], 7.0), (vec![2], 1.0)]
+        );
+    }
+
+    #[test]
+    fn union_of_nothing_is_nothing() {
+        let unioned = union(vec![]).unwrap();
+        assert!(unioned.geometry.is_empty());
+        let unioned = union(vec![Geometry::Point((1.0, 1.0).into())]).unwrap();
+        assert!(unioned.geometry.is_empty());
+    }
+
     #[test]
     fn test_as_edgeset() {
         let p = Polygon::new(